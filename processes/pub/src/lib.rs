@@ -1,14 +1,22 @@
 use anyhow::Result;
 use kinode_process_lib::{
     await_message, call_init, clear_state, get_blob, get_state, kinode::process::standard::OnExit,
-    println, save_capabilities, set_on_exit, set_state, Address, Capability, Message, ProcessId,
-    Request, Response,
+    println, save_capabilities, set_on_exit, set_state, timer::set_timer, Address, Capability,
+    Message, ProcessId, Request, Response, SendError,
 };
 use kinode_pubsub::{
-    InitPubRequest, MessageHistory, PubConfig, PubRequest, PublishRequest, SubscribeResponse,
+    compression, history, AccessControl, BackfillResponse, FetchHistoryRequest,
+    FetchHistoryResponse, Heartbeat, HistoryMessage, InitPubRequest, MessageHistory, Persistence,
+    Principal, PubConfig, PubEvent, PubRequest, PubResponse, PublishRequest, PublishResponse,
+    PublisherStatus, SubRequest, SubResponse, SubscribeRejection, SubscribeResponse,
+    SubscriberDroppedEvent, TopicClosedEvent,
+};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
 };
-use serde::{Deserialize, Serialize};
-use std::{collections::HashSet, str::FromStr};
 
 wit_bindgen::generate!({
     path: "target/wit",
@@ -18,37 +26,157 @@ wit_bindgen::generate!({
 });
 
 const TIMER_PROCESS: &str = "timer:distro:sys";
+/// how often expired history is swept when the topic has a max age.
+const SWEEP_INTERVAL: u64 = 60;
+/// most messages returned by a single fetch-history request.
+const MAX_HISTORY_PAGE: u32 = 1000;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct PublisherState {
     topic: String,
     last_sequence: u64,
+    /// identifies this incarnation of the topic, state saved before epochs existed is 0.
+    #[serde(default)]
+    epoch: u64,
     subscribers: HashSet<Address>,
-    offline_subscribers: HashSet<(Address, u64)>, // (address, retry_count)
+    #[serde(deserialize_with = "offline_subscribers")]
+    offline_subscribers: HashMap<Address, OfflineSubscriber>,
+    #[serde(default)]
+    ack_cursors: HashMap<Address, AckCursor>,
+    #[serde(deserialize_with = "kinode_pubsub::config::deserialize")]
     config: PubConfig,
     parent: Address,
     message_history: MessageHistory,
+    /// set once the topic is removed, the process exits after the current message.
+    #[serde(skip)]
+    closed: bool,
+}
+
+/// A subscriber that stopped answering, retried every retry_interval.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OfflineSubscriber {
+    retry_count: u32,
+    /// first sequence the subscriber may have missed, replayed when it comes back.
+    missed_from: u64,
+}
+
+/// Reads offline subscribers saved as a map, or as the list of
+/// (address, retry_count) pairs saved before they tracked what they missed.
+fn offline_subscribers<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<HashMap<Address, OfflineSubscriber>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Saved {
+        Map(HashMap<Address, OfflineSubscriber>),
+        List(Vec<(Address, u64)>),
+    }
+    Ok(match Saved::deserialize(deserializer)? {
+        Saved::Map(map) => map,
+        Saved::List(list) => list
+            .into_iter()
+            .map(|(address, retry_count)| {
+                let offline = OfflineSubscriber {
+                    retry_count: retry_count as u32,
+                    // unknown, replay all of history, subscribers drop what they already have.
+                    missed_from: 0,
+                };
+                (address, offline)
+            })
+            .collect(),
+    })
+}
+
+/// How far a subscriber has acknowledged the stream.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AckCursor {
+    /// every sequence up to and including this one has been received.
+    acked: u64,
+    /// our last sequence at the previous redelivery check, anything up to it
+    /// still unacked at the next check gets redelivered.
+    deadline_sequence: u64,
+}
+
+/// Timers the publisher arms, told apart by their context.
+#[derive(Debug, Serialize, Deserialize)]
+enum TimerContext {
+    Heartbeat,
+    Retry,
+    Redeliver,
+    Sweep,
+}
+
+/// Context attached to pings, so a SendError tells us which loop it came from.
+#[derive(Debug, Serialize, Deserialize)]
+enum PingContext {
+    /// heartbeat ping to a live subscriber, with our last sequence at the time.
+    Heartbeat(Heartbeat, u64),
+    /// retry ping to an offline subscriber.
+    Retry,
+}
+
+/// A fresh epoch, later incarnations of a topic get larger ones.
+fn new_epoch() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default()
 }
 
 impl PublisherState {
     pub fn new(config: PubConfig, parent: &Address, topic: String) -> Result<Self> {
-        let message_history = MessageHistory::new(parent.clone(), config.default_persistence)?;
+        let message_history = MessageHistory::new(
+            parent.clone(),
+            &topic,
+            config.default_persistence,
+            config.retention,
+        )?;
+        // state can be lost while the history's db survives, continue after what it holds.
+        let last_sequence = message_history
+            .get_latest_sequence()
+            .max(message_history.saved_sequence())
+            .unwrap_or(0);
 
         Ok(PublisherState {
             topic,
             config,
-            last_sequence: 0,
+            last_sequence,
+            epoch: new_epoch(),
             subscribers: HashSet::new(), // what about an initial subscription list?
-            offline_subscribers: HashSet::new(), // then it's more similar to gossip
+            offline_subscribers: HashMap::new(), // then it's more similar to gossip
+            ack_cursors: HashMap::new(),
             parent: parent.clone(),
             message_history,
+            closed: false,
         })
     }
 
     // todo: implement save state at the right moments.
     pub fn load(our: &Address) -> Result<Self> {
-        if let Some(state) = get_state() {
-            if let Ok(state) = serde_json::from_slice::<PublisherState>(&state) {
+        if let Some(bytes) = get_state() {
+            if let Ok(mut state) = serde_json::from_slice::<PublisherState>(&bytes) {
+                // topics from before per-topic dbs move into their own.
+                let topic = state.topic.clone();
+                if let Err(e) = state.message_history.migrate_shared_db(&topic) {
+                    println!("publisher: failed to move history out of the shared db: {e}");
+                }
+                // saved entries can be stale, the kv store is the source of truth.
+                if let Err(e) = state.message_history.rebuild_index() {
+                    // a failed rebuild can leave the index half done, start over from
+                    // the saved entries rather than losing the topic.
+                    println!("publisher: failed to rebuild history index, keeping saved one: {e}");
+                    state = serde_json::from_slice::<PublisherState>(&bytes)?;
+                }
+                // publishes only record their sequence in the history's db, and a crash
+                // before state is saved leaves last_sequence behind, never hand out a
+                // sequence twice.
+                let latest = state
+                    .message_history
+                    .get_latest_sequence()
+                    .max(state.message_history.saved_sequence());
+                if let Some(latest) = latest {
+                    state.last_sequence = state.last_sequence.max(latest);
+                }
                 return Ok(state);
             }
         }
@@ -76,40 +204,200 @@ impl PublisherState {
     }
 }
 
-fn handle_message(message: Message, state: &mut PublisherState) -> Result<()> {
-    let timer_addrress = Address::new("our", ProcessId::from_str(TIMER_PROCESS).unwrap());
+fn handle_message(our: &Address, message: Message, state: &mut PublisherState) -> Result<()> {
+    let timer_addrress = Address::new(our.node(), ProcessId::from_str(TIMER_PROCESS).unwrap());
 
     if message.source() == &timer_addrress {
-        // we should have an automatic loop fire every X seconds with help of the timer.
-        // check heartbeats, retry messages if applicable.
-        return Ok(());
+        let context: TimerContext = serde_json::from_slice(message.context().unwrap_or_default())?;
+        return match context {
+            // heartbeat loop, fires every heartbeat_interval seconds.
+            TimerContext::Heartbeat => heartbeat(state),
+            // retry loop, fires every retry_interval seconds.
+            TimerContext::Retry => retry_offline(state),
+            // redelivery loop, fires every ack_timeout seconds.
+            TimerContext::Redeliver => redeliver_unacked(state),
+            // retention loop, fires every SWEEP_INTERVAL seconds.
+            TimerContext::Sweep => sweep_history(state),
+        };
     }
     if message.is_request() {
         let req: PubRequest = serde_json::from_slice(&message.body())?;
-        handle_request(req, message.source(), state, message.capabilities())?;
+        let expects_response = matches!(
+            message,
+            Message::Request {
+                expects_response: Some(_),
+                ..
+            }
+        );
+        handle_request(
+            our,
+            req,
+            message.source(),
+            state,
+            message.capabilities(),
+            expects_response,
+        )?;
     } else {
-        // maybe as a PONG response...
-        // let res: PubSubResponse = serde_json::from_slice(&message.body())?;
-        // handle_response(res, message.source(), state)?;
+        let res: SubResponse = serde_json::from_slice(&message.body())?;
+        handle_response(res, message.source(), state)?;
+    }
+
+    Ok(())
+}
+
+/// Pings every live subscriber and re-arms the heartbeat timer.
+/// Subscribers that don't pong back within the interval come back as SendErrors.
+fn heartbeat(state: &mut PublisherState) -> Result<()> {
+    let context = serde_json::to_vec(&PingContext::Heartbeat(
+        Heartbeat::Ping,
+        state.last_sequence,
+    ))?;
+    for subscriber in &state.subscribers {
+        Request::to(subscriber)
+            .body(SubRequest::Ping)
+            .expects_response(state.config.heartbeat_interval)
+            .context(context.clone())
+            .send()?;
+    }
+    arm_timer(TimerContext::Heartbeat, state.config.heartbeat_interval);
+    Ok(())
+}
+
+/// Pings every offline subscriber, those that pong back get their missed messages replayed.
+fn retry_offline(state: &mut PublisherState) -> Result<()> {
+    let context = serde_json::to_vec(&PingContext::Retry)?;
+    for subscriber in state.offline_subscribers.keys() {
+        Request::to(subscriber)
+            .body(SubRequest::Ping)
+            .expects_response(state.config.retry_interval)
+            .context(context.clone())
+            .send()?;
+    }
+    arm_timer(TimerContext::Retry, state.config.retry_interval);
+    Ok(())
+}
+
+/// Redelivers history to subscribers whose acks fell behind since the last check.
+fn redeliver_unacked(state: &mut PublisherState) -> Result<()> {
+    for subscriber in &state.subscribers {
+        let Some(cursor) = state.ack_cursors.get(subscriber) else {
+            continue;
+        };
+        if cursor.acked < cursor.deadline_sequence {
+            replay_history(state, subscriber, cursor.acked + 1, state.last_sequence)?;
+        }
+    }
+    // everything published so far should be acked by the next check.
+    for cursor in state.ack_cursors.values_mut() {
+        cursor.deadline_sequence = state.last_sequence;
+    }
+    let _ = state.save();
+    arm_timer(TimerContext::Redeliver, state.config.ack_timeout);
+    Ok(())
+}
+
+/// Makes `last_sequence` durable on its own record in the history's db, rather than
+/// re-serializing the whole state, history included, on every publish. The rest of the
+/// state is saved on the timers and on subscriber changes.
+fn save_sequence(state: &PublisherState) -> Result<()> {
+    if !state.message_history.save_sequence(state.last_sequence)? {
+        state.save()?;
+    }
+    Ok(())
+}
+
+/// Drops expired messages from history, even when nothing new is published.
+fn sweep_history(state: &mut PublisherState) -> Result<()> {
+    state.message_history.sweep()?;
+    let _ = state.save();
+    arm_sweep(&state.config);
+    Ok(())
+}
+
+fn arm_sweep(config: &PubConfig) {
+    // only age limits can expire without a new message coming in.
+    if config.retention.max_age.is_some() {
+        arm_timer(TimerContext::Sweep, SWEEP_INTERVAL);
+    }
+}
+
+/// Arms the timer for one of our loops, an interval of 0 disables that loop.
+fn arm_timer(context: TimerContext, interval: u64) {
+    if interval > 0 {
+        let context = serde_json::to_vec(&context).unwrap();
+        set_timer(interval * 1000, Some(context));
     }
+}
 
+/// Sends every stored message between `from_sequence` and `to_sequence` to `target`.
+fn replay_history(
+    state: &PublisherState,
+    target: &Address,
+    from_sequence: u64,
+    to_sequence: u64,
+) -> Result<()> {
+    let messages = state.message_history.get_messages_from(from_sequence)?;
+    for message in messages
+        .into_iter()
+        .take_while(|message| message.sequence <= to_sequence)
+    {
+        let historical_pub_req = PubRequest::Publish(PublishRequest {
+            topic: state.topic.clone(),
+            sequence: message.sequence,
+            epoch: state.epoch,
+            key: message.key,
+            headers: message.headers,
+        });
+        Request::to(target)
+            .body(&historical_pub_req)
+            .blob_bytes(message.content)
+            .send()?;
+    }
     Ok(())
 }
 
 fn handle_request(
+    our: &Address,
     req: PubRequest,
     source: &Address,
     state: &mut PublisherState,
     caps: &Vec<Capability>,
+    expects_response: bool,
 ) -> Result<()> {
     match req {
         PubRequest::Subscribe(sub_req) => {
-            let (success, error) = if state.topic == sub_req.topic {
+            let rejection = if state.topic != sub_req.topic {
+                Some(SubscribeRejection::UnknownTopic(state.topic.clone()))
+            } else if !is_allowed(&state.config.access, source) {
+                Some(SubscribeRejection::AccessDenied)
+            } else {
+                None
+            };
+            let (success, error) = if rejection.is_none() {
+                state.offline_subscribers.remove(source);
                 state.subscribers.insert(source.clone());
+                state.ack_cursors.insert(
+                    source.clone(),
+                    AckCursor {
+                        acked: match sub_req.from_sequence {
+                            Some(from_sequence) => from_sequence.saturating_sub(1),
+                            None => state.last_sequence,
+                        },
+                        deadline_sequence: state.last_sequence,
+                    },
+                );
                 // save messaging cap!
                 save_capabilities(caps.as_slice());
                 let _ = state.save();
                 (true, None)
+            } else if let Some(SubscribeRejection::AccessDenied) = rejection {
+                (
+                    false,
+                    Some(format!(
+                        "error: {} is not allowed to subscribe to topic: {}",
+                        source, state.topic
+                    )),
+                )
             } else {
                 (
                     false,
@@ -123,30 +411,22 @@ fn handle_request(
                 success,
                 topic: sub_req.topic,
                 error,
+                rejection,
+                latest_sequence: state.last_sequence,
+                epoch: state.epoch,
             };
             Response::new().body(res).send()?;
 
             // send historical messages too if requested.
-            if success && sub_req.from_sequence.is_some() {
-                let messages = state
-                    .message_history
-                    .get_messages_from(sub_req.from_sequence.unwrap())?;
-                for message in messages {
-                    // Send historical messages to the new subscriber
-                    let historical_pub_req = PubRequest::Publish(PublishRequest {
-                        topic: state.topic.clone(),
-                        sequence: message.sequence,
-                    });
-                    Request::to(source)
-                        .body(&historical_pub_req)
-                        .blob_bytes(message.content)
-                        .send()?;
-                }
+            if let (true, Some(from_sequence)) = (success, sub_req.from_sequence) {
+                replay_history(state, source, from_sequence, state.last_sequence)?;
             }
         }
         PubRequest::Unsubscribe(unsub_req) => {
             let (success, error) = if state.topic == unsub_req.topic {
                 state.subscribers.remove(source);
+                state.offline_subscribers.remove(source);
+                state.ack_cursors.remove(source);
                 let _ = state.save();
                 (true, None)
             } else {
@@ -158,31 +438,72 @@ fn handle_request(
                     )),
                 )
             };
+            let rejection =
+                (!success).then(|| SubscribeRejection::UnknownTopic(state.topic.clone()));
             let res = SubscribeResponse {
                 success,
                 topic: unsub_req.topic,
                 error,
+                rejection,
+                latest_sequence: state.last_sequence,
+                epoch: state.epoch,
             };
             Response::new().body(&res).send()?;
         }
         PubRequest::Publish(mut pub_msg) => {
             if source == &state.parent {
-                // 1. Fetch and increment sequence number
-                state.last_sequence += 1;
-                let new_seq = state.last_sequence;
+                // 1. Fetch the next sequence number
+                let new_seq = state.last_sequence + 1;
 
                 let bytes = if let Some(blob) = get_blob() {
                     blob.bytes
                 } else {
                     vec![]
                 };
+                // compress once, history and subscribers all get the compressed payload.
+                let bytes =
+                    compression::compress(state.config.compression, &mut pub_msg.headers, bytes)?;
 
                 // store message (if persistence is enabled)
                 // doublecheck blob behaviour/persistence here (if none, no need to bring in and clone...)
-                state.message_history.add_message(new_seq, bytes.clone())?;
+                // a rejected message (e.g. over the byte budget) doesn't use up a sequence number.
+                let mut message = history::Message::new(new_seq, bytes.clone());
+                message.key = pub_msg.key.clone();
+                message.headers = pub_msg.headers.clone();
+                if let Err(e) = state.message_history.add_message(message) {
+                    if expects_response {
+                        let res = PubResponse::Publish(PublishResponse {
+                            topic: state.topic.clone(),
+                            sequence: 0,
+                            epoch: state.epoch,
+                            stored: false,
+                            error: Some(e.to_string()),
+                        });
+                        Response::new().body(res).send()?;
+                    }
+                    return Err(e);
+                }
+                state.last_sequence = new_seq;
+                // persist the sequence before any subscriber can see it.
+                save_sequence(state)?;
+
+                if expects_response {
+                    let res = PubResponse::Publish(PublishResponse {
+                        topic: state.topic.clone(),
+                        sequence: new_seq,
+                        epoch: state.epoch,
+                        stored: !matches!(
+                            state.message_history.persistence_type(),
+                            Persistence::None
+                        ),
+                        error: None,
+                    });
+                    Response::new().body(res).send()?;
+                }
 
                 // distribute to subscribers!
                 pub_msg.sequence = new_seq;
+                pub_msg.epoch = state.epoch;
                 let req = PubRequest::Publish(pub_msg);
 
                 for subscriber in &state.subscribers {
@@ -191,23 +512,315 @@ fn handle_request(
                         .blob_bytes(bytes.clone())
                         .send()?;
                 }
+            } else if expects_response {
+                let res = PubResponse::Publish(PublishResponse {
+                    topic: pub_msg.topic,
+                    sequence: 0,
+                    epoch: state.epoch,
+                    stored: false,
+                    error: Some("error: only the topic's parent can publish".to_string()),
+                });
+                Response::new().body(res).send()?;
+            }
+        }
+        PubRequest::PublishBatch(mut batch) => {
+            if source == &state.parent {
+                let bytes = get_blob().map(|blob| blob.bytes).unwrap_or_default();
+                let total: u64 = batch.sizes.iter().sum();
+                if total != bytes.len() as u64 {
+                    return Err(anyhow::anyhow!(
+                        "batch sizes add up to {} bytes, payload has {}",
+                        total,
+                        bytes.len()
+                    ));
+                }
+
+                // the batch gets a contiguous range, stored all at once or not at all.
+                // messages are compressed one by one, so history can replay each alone.
+                let first_seq = state.last_sequence + 1;
+                let shared_headers = batch.headers.clone();
+                let mut offset = 0;
+                let mut compressed = Vec::with_capacity(bytes.len());
+                let mut messages = Vec::with_capacity(batch.sizes.len());
+                for (i, size) in batch.sizes.iter_mut().enumerate() {
+                    let end = offset + *size as usize;
+                    let mut headers = shared_headers.clone();
+                    let content = compression::compress(
+                        state.config.compression,
+                        &mut headers,
+                        bytes[offset..end].to_vec(),
+                    )?;
+                    offset = end;
+
+                    *size = content.len() as u64;
+                    compressed.extend_from_slice(&content);
+                    let mut message = history::Message::new(first_seq + i as u64, content);
+                    message.headers = headers.clone();
+                    messages.push(message);
+                    batch.headers = headers;
+                }
+                let bytes = compressed;
+                state.message_history.add_messages(messages)?;
+                state.last_sequence += batch.sizes.len() as u64;
+                save_sequence(state)?;
+
+                // one delivery per subscriber, unpacked by the subscriber process.
+                batch.first_sequence = first_seq;
+                batch.epoch = state.epoch;
+                let req = PubRequest::PublishBatch(batch);
+
+                for subscriber in &state.subscribers {
+                    Request::to(subscriber)
+                        .body(&req)
+                        .blob_bytes(bytes.clone())
+                        .send()?;
+                }
+            }
+        }
+        PubRequest::Backfill(backfill) => {
+            if state.topic != backfill.topic || !state.subscribers.contains(source) {
+                // e.g. just marked offline. without a response the request
+                // times out, and the subscriber asks again later.
+                return Ok(());
+            }
+            replay_history(state, source, backfill.from_sequence, backfill.to_sequence)?;
+            // responding after the replay tells the subscriber that whatever
+            // it still lacks in the range is gone from history.
+            let res = SubResponse::Backfill(BackfillResponse {
+                topic: backfill.topic,
+                from_sequence: backfill.from_sequence,
+                to_sequence: backfill.to_sequence,
+                first_available: state.message_history.first_available_sequence(),
+            });
+            Response::new().body(res).send()?;
+        }
+        PubRequest::Ack(ack) => {
+            if state.topic == ack.topic {
+                if let Some(cursor) = state.ack_cursors.get_mut(source) {
+                    cursor.acked = cursor.acked.max(ack.sequence);
+                }
+            }
+        }
+        PubRequest::FetchHistory(fetch) => {
+            let res = fetch_history(fetch, source, state)?;
+            Response::new()
+                .body(PubResponse::FetchHistory(res))
+                .send()?;
+        }
+        PubRequest::SetAccess(access) => {
+            if source == &state.parent {
+                state.config.access = access;
+                // subscribers that lost access are dropped right away, and told
+                // the topic is closed to them so their processes stop waiting.
+                let closed = SubRequest::TopicClosed(TopicClosedEvent {
+                    topic: state.topic.clone(),
+                    publisher: our.to_string(),
+                });
+                let denied: Vec<Address> = state
+                    .subscribers
+                    .iter()
+                    .chain(state.offline_subscribers.keys())
+                    .filter(|subscriber| !is_allowed(&state.config.access, subscriber))
+                    .cloned()
+                    .collect();
+                for subscriber in denied {
+                    println!(
+                        "publisher: {} lost access to topic {}, dropping",
+                        subscriber, state.topic
+                    );
+                    state.subscribers.remove(&subscriber);
+                    state.offline_subscribers.remove(&subscriber);
+                    state.ack_cursors.remove(&subscriber);
+                    Request::to(&subscriber).body(&closed).send()?;
+                }
+                state.save()?;
+            }
+        }
+        PubRequest::Status => {
+            if source == &state.parent {
+                let status = PublisherStatus {
+                    topic: state.topic.clone(),
+                    config: state.config.clone(),
+                    last_sequence: state.last_sequence,
+                    epoch: state.epoch,
+                };
+                Response::new().body(PubResponse::Status(status)).send()?;
             }
         }
         PubRequest::Kill => {
+            if source != &state.parent {
+                return Ok(());
+            }
+            // let every subscriber, offline ones included, know they can stop waiting.
+            let closed = SubRequest::TopicClosed(TopicClosedEvent {
+                topic: state.topic.clone(),
+                publisher: our.to_string(),
+            });
+            for subscriber in state
+                .subscribers
+                .iter()
+                .chain(state.offline_subscribers.keys())
+            {
+                Request::to(subscriber).body(&closed).send()?;
+            }
+
             set_on_exit(&OnExit::None);
+            if let Err(e) = state.message_history.destroy() {
+                println!("publisher: failed to remove history: {e}");
+            }
             let _ = state.clear();
-            panic!("publisher got kill request, exiting and not restarting");
+            state.closed = true;
         }
         _ => {}
     }
     Ok(())
 }
 
-fn _handle_response(
-    _res: PubRequest,
-    _source: &Address,
-    _state: &mut PublisherState,
-) -> Result<()> {
+/// Whether the access control lets `source` subscribe and read history.
+fn is_allowed(access: &AccessControl, source: &Address) -> bool {
+    let matches = |principal: &Principal| match principal {
+        Principal::Node(node) => source.node() == node,
+        Principal::Package(package) => source.package_id().to_string() == *package,
+    };
+    match access {
+        AccessControl::Public => true,
+        AccessControl::Allowlist(principals) => principals.iter().any(matches),
+        AccessControl::Denylist(principals) => !principals.iter().any(matches),
+    }
+}
+
+/// Reads a page of history, open to anyone allowed to subscribe.
+fn fetch_history(
+    fetch: FetchHistoryRequest,
+    source: &Address,
+    state: &PublisherState,
+) -> Result<FetchHistoryResponse> {
+    if !is_allowed(&state.config.access, source) {
+        return Ok(FetchHistoryResponse {
+            error: Some(format!(
+                "error: {} is not allowed to read topic: {}",
+                source, state.topic
+            )),
+            topic: fetch.topic,
+            messages: vec![],
+            next_cursor: None,
+        });
+    }
+    if state.topic != fetch.topic {
+        return Ok(FetchHistoryResponse {
+            error: Some(format!(
+                "error: publisher does not have requested topic: {}, has: {}",
+                fetch.topic, state.topic
+            )),
+            topic: fetch.topic,
+            messages: vec![],
+            next_cursor: None,
+        });
+    }
+
+    let start = fetch
+        .start_sequence
+        .unwrap_or(0)
+        .max(fetch.cursor.unwrap_or(0));
+    let end = fetch.end_sequence.unwrap_or(state.last_sequence);
+    let limit = match fetch.limit {
+        0 => MAX_HISTORY_PAGE,
+        limit => limit.min(MAX_HISTORY_PAGE),
+    };
+
+    let (messages, next_cursor) = state
+        .message_history
+        .get_range(start, end, limit as usize)?;
+
+    Ok(FetchHistoryResponse {
+        topic: fetch.topic,
+        messages: messages
+            .into_iter()
+            .map(|message| HistoryMessage {
+                sequence: message.sequence,
+                timestamp: message.timestamp,
+                key: message.key,
+                headers: message.headers,
+                payload: message.content,
+            })
+            .collect(),
+        next_cursor,
+        error: None,
+    })
+}
+
+fn handle_response(res: SubResponse, source: &Address, state: &mut PublisherState) -> Result<()> {
+    if let SubResponse::Pong = res {
+        // an offline subscriber answered again, catch it up and bring it back.
+        if let Some(offline) = state.offline_subscribers.remove(source) {
+            replay_history(state, source, offline.missed_from, state.last_sequence)?;
+            state.subscribers.insert(source.clone());
+            let _ = state.save();
+        }
+    }
+    Ok(())
+}
+
+fn handle_send_error(send_error: &SendError, state: &mut PublisherState) -> Result<()> {
+    let target = send_error.target();
+    let context = send_error
+        .context()
+        .and_then(|c| serde_json::from_slice::<PingContext>(c).ok());
+
+    // first sequence this failure could have cost the subscriber.
+    let missed_from = match (
+        &context,
+        serde_json::from_slice::<PubRequest>(send_error.message().body()),
+    ) {
+        (Some(PingContext::Heartbeat(_, sequence)), _) => sequence + 1,
+        (_, Ok(PubRequest::Publish(pub_msg))) => pub_msg.sequence,
+        (_, Ok(PubRequest::PublishBatch(batch))) => batch.first_sequence,
+        _ => state.last_sequence + 1,
+    };
+
+    if state.subscribers.remove(target) {
+        println!(
+            "publisher: subscriber {} unreachable ({:?}), marking offline",
+            target,
+            send_error.kind()
+        );
+        state.offline_subscribers.insert(
+            target.clone(),
+            OfflineSubscriber {
+                retry_count: 0,
+                missed_from,
+            },
+        );
+        state.save()?;
+        return Ok(());
+    }
+
+    let Some(offline) = state.offline_subscribers.get_mut(target) else {
+        return Ok(());
+    };
+    if !matches!(context, Some(PingContext::Retry)) {
+        // a straggling publish failure, only move the replay point back.
+        offline.missed_from = offline.missed_from.min(missed_from);
+        state.save()?;
+        return Ok(());
+    }
+
+    offline.retry_count += 1;
+    if offline.retry_count >= state.config.max_retry_attempts {
+        println!(
+            "publisher: subscriber {} unreachable after {} retries, dropping",
+            target, offline.retry_count
+        );
+        let event = PubEvent::SubscriberDropped(SubscriberDroppedEvent {
+            topic: state.topic.clone(),
+            subscriber: target.to_string(),
+            retry_attempts: offline.retry_count,
+        });
+        state.offline_subscribers.remove(target);
+        state.ack_cursors.remove(target);
+        Request::to(&state.parent).body(&event).send()?;
+    }
+    state.save()?;
     Ok(())
 }
 
@@ -228,15 +841,27 @@ fn init(our: Address) {
     };
 
     let _ = state.save();
+    arm_timer(TimerContext::Heartbeat, state.config.heartbeat_interval);
+    arm_timer(TimerContext::Retry, state.config.retry_interval);
+    arm_timer(TimerContext::Redeliver, state.config.ack_timeout);
+    arm_sweep(&state.config);
 
     loop {
         match await_message() {
-            Err(send_error) => println!("publisher: got SendError: {send_error}"),
+            Err(send_error) => {
+                if let Err(e) = handle_send_error(&send_error, &mut state) {
+                    println!("publisher: error handling SendError: {e}");
+                }
+            }
             Ok(message) => {
-                if let Err(e) = handle_message(message, &mut state) {
+                if let Err(e) = handle_message(&our, message, &mut state) {
                     println!("publisher: got error: {e}");
                 }
             }
         }
+        if state.closed {
+            println!("publisher: topic {} removed, exiting", state.topic);
+            return;
+        }
     }
 }
@@ -1,13 +1,21 @@
 use anyhow::Result;
 use kinode::process::standard::clear_state;
 use kinode_process_lib::{
-    await_message, call_init, get_capability, get_state, kinode::process::standard::OnExit,
-    println, set_on_exit, set_state, Address, Message, ProcessId, Request, Response,
+    await_message, call_init, get_blob, get_capability, get_state,
+    kinode::process::standard::OnExit, println, set_on_exit, set_state, Address, Message,
+    ProcessId, Request, Response, SendError, SendErrorKind,
 };
 use serde::{Deserialize, Serialize};
-use std::{collections::HashSet, str::FromStr};
+use std::{
+    collections::{BTreeMap, HashSet},
+    str::FromStr,
+};
 
-use kinode_pubsub::{InitSubRequest, SubRequest, SubResponse, SubscribeRequest, SubscribeResponse};
+use kinode_pubsub::{
+    compression, encryption, AckRequest, BackfillRequest, BackfillResponse, GapEvent,
+    InitSubRequest, PubRequest, PublishRequest, SubRequest, SubResponse, SubscribeRequest,
+    SubscribeResponse, TopicKey, TopicResetEvent,
+};
 
 const TIMER_PROCESS: &str = "timer:distro:sys";
 
@@ -21,6 +29,24 @@ wit_bindgen::generate!({
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SubscriberState {
     subscription: Subscription,
+    /// out-of-order messages, held back until the gap before them is filled.
+    #[serde(default)]
+    reorder_buffer: BTreeMap<u64, BufferedMessage>,
+    /// range we've asked the publisher to backfill, if any.
+    #[serde(default)]
+    pending_backfill: Option<(u64, u64)>,
+    /// keys of an encrypted topic, one per key-epoch.
+    #[serde(default)]
+    keys: Vec<TopicKey>,
+    /// set once the publisher closed the topic, the process exits after the current message.
+    #[serde(skip)]
+    closed: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BufferedMessage {
+    pub request: PublishRequest,
+    pub payload: Vec<u8>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -28,13 +54,22 @@ pub struct Subscription {
     pub publisher: Address,
     pub topic: String,
     pub last_received_seq: u64,
+    /// publisher incarnation our cursor belongs to.
+    #[serde(default)]
+    pub epoch: u64,
     pub parent: Address,
     pub forward_to: HashSet<Address>,
 }
 
 impl SubscriberState {
-    pub fn new(sub: Subscription) -> Self {
-        SubscriberState { subscription: sub }
+    pub fn new(sub: Subscription, keys: Vec<TopicKey>) -> Self {
+        SubscriberState {
+            subscription: sub,
+            reorder_buffer: BTreeMap::new(),
+            pending_backfill: None,
+            keys,
+            closed: false,
+        }
     }
 
     pub fn save(&self) -> Result<()> {
@@ -90,18 +125,28 @@ impl SubscriberState {
             return Err(anyhow::anyhow!("Subscription failed"));
         }
 
-        Ok(SubscriberState::new(Subscription {
-            parent,
-            publisher,
-            topic: resp.topic,
-            last_received_seq: req.from_sequence.unwrap_or(0),
-            forward_to,
-        }))
+        // replays start at from_sequence, live messages right after the latest one.
+        let last_received_seq = match req.from_sequence {
+            Some(from_sequence) => from_sequence.saturating_sub(1),
+            None => resp.latest_sequence,
+        };
+
+        Ok(SubscriberState::new(
+            Subscription {
+                parent,
+                publisher,
+                topic: resp.topic,
+                last_received_seq,
+                epoch: resp.epoch,
+                forward_to,
+            },
+            req.keys,
+        ))
     }
 }
 
 fn handle_message(our: &Address, message: Message, state: &mut SubscriberState) -> Result<()> {
-    let timer_addrress = Address::new("our", ProcessId::from_str(TIMER_PROCESS).unwrap());
+    let timer_addrress = Address::new(our.node(), ProcessId::from_str(TIMER_PROCESS).unwrap());
 
     if message.source() == &timer_addrress {
         // we should have an automatic loop fire every X seconds with help of the timer.
@@ -150,34 +195,87 @@ fn handle_request(
             }
         }
         SubRequest::Publish(pub_msg) => {
-            if state.subscription.topic == pub_msg.topic {
-                state.subscription.last_received_seq = pub_msg.sequence;
-                // println!("sub: got message. seq: {}", pub_msg.sequence);
-
-                // Forward to parent
-                Request::to(&state.subscription.parent)
-                    .body(&req)
-                    .inherit(true)
-                    .send()?;
-
-                // Forward to other subscribers
+            if state.subscription.topic == pub_msg.topic && accept_epoch(state, pub_msg.epoch)? {
+                let payload = get_blob().map(|blob| blob.bytes).unwrap_or_default();
+                receive(
+                    state,
+                    BufferedMessage {
+                        request: pub_msg.clone(),
+                        payload,
+                    },
+                )?;
+                // ack even duplicates, ours might have been lost.
+                ack(state)?;
+                let _ = state.save();
+            }
+        }
+        SubRequest::PublishBatch(batch) => {
+            if state.subscription.topic == batch.topic && accept_epoch(state, batch.epoch)? {
+                let mut payloads = get_blob().map(|blob| blob.bytes).unwrap_or_default();
+                // unpack back to front, so every split_off leaves the earlier payloads.
+                let mut messages = Vec::with_capacity(batch.sizes.len());
+                for (i, size) in batch.sizes.iter().enumerate().rev() {
+                    let at = payloads
+                        .len()
+                        .checked_sub(*size as usize)
+                        .ok_or(anyhow::anyhow!("batch sizes don't match its payload"))?;
+                    messages.push(BufferedMessage {
+                        request: PublishRequest {
+                            topic: batch.topic.clone(),
+                            sequence: batch.first_sequence + i as u64,
+                            epoch: batch.epoch,
+                            key: None,
+                            headers: batch.headers.clone(),
+                        },
+                        payload: payloads.split_off(at),
+                    });
+                }
+                for message in messages.into_iter().rev() {
+                    receive(state, message)?;
+                }
+                ack(state)?;
+                let _ = state.save();
+            }
+        }
+        SubRequest::SetKey(key) => {
+            if source == &state.subscription.parent {
+                state
+                    .keys
+                    .retain(|existing| existing.key_epoch != key.key_epoch);
+                state.keys.push(key.clone());
+                let _ = state.save();
+            }
+        }
+        SubRequest::TopicClosed(_) => {
+            if source == &state.subscription.publisher {
+                println!(
+                    "subscriber: publisher closed topic {}, exiting",
+                    state.subscription.topic
+                );
+                Request::to(&state.subscription.parent).body(&req).send()?;
                 for forward_to in &state.subscription.forward_to {
-                    Request::to(forward_to).body(&req).inherit(true).send()?;
+                    Request::to(forward_to).body(&req).send()?;
                 }
+                set_on_exit(&OnExit::None);
+                state.clear();
+                state.closed = true;
+            }
+        }
+        SubRequest::Ping => {
+            // the publisher's heartbeat, or our parent checking we're alive after a restart.
+            if source == &state.subscription.publisher || source == &state.subscription.parent {
+                Response::new().body(SubResponse::Pong).send()?;
+            }
+            if source == &state.subscription.publisher {
+                // retries a backfill that failed, if messages are still held back.
+                request_backfill(state)?;
             }
         }
-        SubRequest::Subscribe(_sub_req) => {
-            // TODO: no send_and_await in this resubscribe
-            // currently just shooting it away, ignoring response
+        SubRequest::Subscribe(sub_req) => {
             if source == &state.subscription.parent {
-                let messaging_cap = get_capability(our, "\"messaging\"").ok_or(anyhow::anyhow!(
-                    "Subscriber failed to get messaging capability"
-                ))?;
-
-                Request::to(&state.subscription.publisher)
-                    .body(&req)
-                    .capabilities(vec![messaging_cap])
-                    .send()?;
+                let resp = resubscribe(our, &req, sub_req, state)?;
+                // hand the publisher's response back, the parent turns failures into errors.
+                Response::new().body(&resp).send()?;
             }
         }
         _ => {}
@@ -185,15 +283,282 @@ fn handle_request(
     Ok(())
 }
 
-fn handle_response(
-    _res: SubResponse,
-    _source: &Address,
-    _state: &mut SubscriberState,
-) -> Result<()> {
-    // might need ping and pongs.
+/// Forwards our parent's resubscribe to the publisher and waits for its answer.
+/// Our cursor only moves once the publisher has accepted, replays are queued behind its response.
+fn resubscribe(
+    our: &Address,
+    req: &SubRequest,
+    sub_req: &SubscribeRequest,
+    state: &mut SubscriberState,
+) -> Result<SubscribeResponse> {
+    let failed = |error: String| SubscribeResponse {
+        success: false,
+        topic: sub_req.topic.clone(),
+        error: Some(error),
+        rejection: None,
+        latest_sequence: 0,
+        epoch: 0,
+    };
+
+    let messaging_cap = get_capability(our, "\"messaging\"").ok_or(anyhow::anyhow!(
+        "Subscriber failed to get messaging capability"
+    ))?;
+    let response = match Request::to(&state.subscription.publisher)
+        .body(req)
+        .capabilities(vec![messaging_cap])
+        .send_and_await_response(10)?
+    {
+        Ok(response) => response,
+        Err(send_error) => return Ok(failed(send_error.to_string())),
+    };
+    let resp: SubscribeResponse = match serde_json::from_slice(&response.body()) {
+        Ok(resp) => resp,
+        Err(e) => return Ok(failed(e.to_string())),
+    };
+    if !resp.success {
+        return Ok(resp);
+    }
+
+    let previous_epoch = state.subscription.epoch;
+    accept_epoch(state, resp.epoch)?;
+    let reset = state.subscription.epoch != previous_epoch && previous_epoch != 0;
+    match sub_req.from_sequence {
+        Some(from_sequence) => {
+            // the publisher replays from here, rewind our cursor to match.
+            state.subscription.last_received_seq = from_sequence.saturating_sub(1);
+            state.reorder_buffer.clear();
+            state.pending_backfill = None;
+        }
+        // a reset topic starts over, live messages come right after the latest one.
+        None if reset => state.subscription.last_received_seq = resp.latest_sequence,
+        None => {}
+    }
+    let _ = state.save();
+    Ok(resp)
+}
+
+fn handle_response(res: SubResponse, source: &Address, state: &mut SubscriberState) -> Result<()> {
+    if source != &state.subscription.publisher {
+        return Ok(());
+    }
+    if let SubResponse::Backfill(backfill) = res {
+        handle_backfill_response(backfill, state)?;
+        ack(state)?;
+        let _ = state.save();
+    }
     Ok(())
 }
 
+/// Forwards a message to the parent and the forward_to list, advancing our cursor.
+/// Encrypted and compressed payloads are decrypted and decompressed first, receivers get the original.
+/// Without the key, the payload is delivered still encrypted, with its `pubsub-encryption` header.
+/// Likewise, a payload that fails to decompress keeps its `pubsub-content-encoding` header.
+fn deliver(state: &mut SubscriberState, message: BufferedMessage) -> Result<()> {
+    let mut request = message.request;
+    let mut payload = message.payload;
+    if encryption::is_encrypted(&request.headers) {
+        // headers are only touched once decryption succeeds.
+        match encryption::decrypt(
+            &state.keys,
+            &state.subscription.topic,
+            &mut request.headers,
+            payload.clone(),
+        ) {
+            Ok(plaintext) => payload = plaintext,
+            Err(e) => println!(
+                "subscriber: can't decrypt message {} on topic {}: {e}",
+                request.sequence, state.subscription.topic
+            ),
+        }
+    }
+    if !encryption::is_encrypted(&request.headers) {
+        // likewise, a payload that fails to decompress is delivered as it is.
+        match compression::decompress(&mut request.headers, payload.clone()) {
+            Ok(decompressed) => payload = decompressed,
+            Err(e) => println!(
+                "subscriber: can't decompress message {} on topic {}: {e}",
+                request.sequence, state.subscription.topic
+            ),
+        }
+    }
+    state.subscription.last_received_seq = request.sequence;
+    let req = SubRequest::Publish(request);
+
+    Request::to(&state.subscription.parent)
+        .body(&req)
+        .blob_bytes(payload.clone())
+        .send()?;
+
+    for forward_to in &state.subscription.forward_to {
+        Request::to(forward_to)
+            .body(&req)
+            .blob_bytes(payload.clone())
+            .send()?;
+    }
+    Ok(())
+}
+
+/// Checks a message's epoch against ours, resetting on a newer one.
+/// Returns false for stragglers from the publisher's previous incarnation.
+fn accept_epoch(state: &mut SubscriberState, epoch: u64) -> Result<bool> {
+    if epoch < state.subscription.epoch {
+        return Ok(false);
+    }
+    if state.subscription.epoch == 0 {
+        // saved before epochs existed, adopt the publisher's.
+        state.subscription.epoch = epoch;
+    } else if epoch > state.subscription.epoch {
+        reset_topic(state, epoch)?;
+    }
+    Ok(true)
+}
+
+/// Delivers a message if it's next in line, holds it back if it's early,
+/// and drops it if it was already delivered.
+fn receive(state: &mut SubscriberState, message: BufferedMessage) -> Result<()> {
+    let sequence = message.request.sequence;
+    let expected = state.subscription.last_received_seq + 1;
+    if sequence < expected {
+        return Ok(());
+    }
+    if sequence > expected {
+        // hold it back and ask the publisher for what's missing.
+        state.reorder_buffer.insert(sequence, message);
+        request_backfill(state)
+    } else {
+        deliver(state, message)?;
+        flush_reorder_buffer(state)
+    }
+}
+
+/// The publisher was re-created and its sequences restarted,
+/// drop our cursor and anything buffered, and tell the parent.
+fn reset_topic(state: &mut SubscriberState, epoch: u64) -> Result<()> {
+    println!(
+        "subscriber: topic {} was reset, epoch {} -> {}",
+        state.subscription.topic, state.subscription.epoch, epoch
+    );
+    let reset = SubRequest::TopicReset(TopicResetEvent {
+        topic: state.subscription.topic.clone(),
+        previous_epoch: state.subscription.epoch,
+        epoch,
+    });
+    state.subscription.epoch = epoch;
+    state.subscription.last_received_seq = 0;
+    state.reorder_buffer.clear();
+    state.pending_backfill = None;
+
+    Request::to(&state.subscription.parent)
+        .body(&reset)
+        .send()?;
+    for forward_to in &state.subscription.forward_to {
+        Request::to(forward_to).body(&reset).send()?;
+    }
+    Ok(())
+}
+
+/// Acknowledges everything up to our cursor, the publisher redelivers anything past it.
+fn ack(state: &SubscriberState) -> Result<()> {
+    let req = PubRequest::Ack(AckRequest {
+        topic: state.subscription.topic.clone(),
+        sequence: state.subscription.last_received_seq,
+    });
+    Request::to(&state.subscription.publisher).body(&req).send()
+}
+
+/// Delivers buffered messages for as long as they line up with our cursor.
+fn flush_reorder_buffer(state: &mut SubscriberState) -> Result<()> {
+    while let Some(entry) = state.reorder_buffer.first_entry() {
+        let sequence = *entry.key();
+        if sequence <= state.subscription.last_received_seq {
+            entry.remove();
+        } else if sequence == state.subscription.last_received_seq + 1 {
+            let message = entry.remove();
+            deliver(state, message)?;
+        } else {
+            break;
+        }
+    }
+    request_backfill(state)
+}
+
+/// Asks the publisher for the range between our cursor and the first buffered message.
+fn request_backfill(state: &mut SubscriberState) -> Result<()> {
+    if state.pending_backfill.is_some() {
+        return Ok(());
+    }
+    let Some(first_buffered) = state.reorder_buffer.keys().next() else {
+        return Ok(());
+    };
+    let range = (state.subscription.last_received_seq + 1, first_buffered - 1);
+
+    let req = PubRequest::Backfill(BackfillRequest {
+        topic: state.subscription.topic.clone(),
+        from_sequence: range.0,
+        to_sequence: range.1,
+    });
+    Request::to(&state.subscription.publisher)
+        .body(&req)
+        .expects_response(30)
+        .send()?;
+    state.pending_backfill = Some(range);
+    Ok(())
+}
+
+/// A backfill request that went unanswered, e.g. because the publisher had just marked
+/// us offline, is asked again right away, held back messages would wait forever otherwise.
+/// One that couldn't be delivered is retried on the publisher's next heartbeat or message.
+fn handle_send_error(send_error: &SendError, state: &mut SubscriberState) -> Result<()> {
+    let Ok(PubRequest::Backfill(_)) =
+        serde_json::from_slice::<PubRequest>(send_error.message().body())
+    else {
+        return Ok(());
+    };
+    state.pending_backfill = None;
+    if let SendErrorKind::Timeout = send_error.kind() {
+        request_backfill(state)?;
+    }
+    Ok(())
+}
+
+/// The publisher has replayed everything it still has for the range,
+/// whatever is still missing is either superseded (compacted topics)
+/// or gone: tell the parent about the latter and move past both.
+fn handle_backfill_response(res: BackfillResponse, state: &mut SubscriberState) -> Result<()> {
+    state.pending_backfill = None;
+
+    let from_sequence = state.subscription.last_received_seq + 1;
+    let missing_to = match state.reorder_buffer.keys().next() {
+        Some(first_buffered) => res.to_sequence.min(first_buffered - 1),
+        None => res.to_sequence,
+    };
+    let to_sequence = match res.first_available {
+        Some(first_available) => missing_to.min(first_available.saturating_sub(1)),
+        None => missing_to,
+    };
+
+    if from_sequence <= to_sequence {
+        println!(
+            "subscriber: messages {}..={} on topic {} are lost",
+            from_sequence, to_sequence, state.subscription.topic
+        );
+        let gap = SubRequest::Gap(GapEvent {
+            topic: state.subscription.topic.clone(),
+            from_sequence,
+            to_sequence,
+        });
+        Request::to(&state.subscription.parent).body(&gap).send()?;
+        for forward_to in &state.subscription.forward_to {
+            Request::to(forward_to).body(&gap).send()?;
+        }
+    }
+    if from_sequence <= missing_to {
+        state.subscription.last_received_seq = missing_to;
+    }
+
+    flush_reorder_buffer(state)
+}
+
 call_init!(init);
 fn init(our: Address) {
     println!("subscriber init");
@@ -210,12 +575,20 @@ fn init(our: Address) {
     let _ = state.save();
     loop {
         match await_message() {
-            Err(send_error) => println!("subscriber: got SendError: {send_error}"),
+            Err(send_error) => {
+                println!("subscriber: got SendError: {send_error}");
+                if let Err(e) = handle_send_error(&send_error, &mut state) {
+                    println!("subscriber: error handling SendError: {e}");
+                }
+            }
             Ok(message) => {
                 if let Err(e) = handle_message(&our, message, &mut state) {
                     println!("subscriber: error handling message: {e}");
                 }
             }
         }
+        if state.closed {
+            return;
+        }
     }
 }
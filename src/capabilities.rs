@@ -0,0 +1,130 @@
+use kinode_process_lib::{our_capabilities, Address, Capability};
+
+use crate::binary_helpers::WasmType;
+
+/// Capabilities a spawned child is given, out of the ones its parent holds.
+/// Anything else the parent can reach stays out of the child's reach.
+///
+/// Both children get:
+/// - messaging the parent, for events and forwarded messages.
+/// - net:distro:sys, to reach publishers and subscribers on other nodes.
+///
+/// Publishers also get:
+/// - kv:distro:sys, to open their own history db. The kv grants read/write
+///   on a db to the process that creates it, so no db capabilities are passed.
+/// - timer:distro:sys, for the heartbeat, retry, redelivery and sweep loops.
+///
+/// Subscribers keep their state with set_state and set no timers, so they get neither.
+/// The messaging capabilities publishers and subscribers need for each other are
+/// exchanged at subscribe time, not inherited.
+pub fn child_capabilities(our: &Address, child: WasmType) -> Vec<Capability> {
+    minimal_capabilities(our, child, our_capabilities())
+}
+
+/// Filters `caps` down to the set documented on `child_capabilities`.
+pub fn minimal_capabilities(
+    our: &Address,
+    child: WasmType,
+    caps: Vec<Capability>,
+) -> Vec<Capability> {
+    let system: &[&str] = match child {
+        WasmType::Pub => &["net:distro:sys", "kv:distro:sys", "timer:distro:sys"],
+        WasmType::Sub => &["net:distro:sys"],
+    };
+
+    caps.into_iter()
+        .filter(|cap| cap.params == "\"messaging\"")
+        .filter(|cap| {
+            &cap.issuer == our
+                || (cap.issuer.node == our.node
+                    && system.contains(&cap.issuer.process.to_string().as_str()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kinode_process_lib::ProcessId;
+
+    fn address(node: &str, process: &str, package: &str, publisher: &str) -> Address {
+        Address::new(node, ProcessId::new(Some(process), package, publisher))
+    }
+
+    fn messaging(issuer: Address) -> Capability {
+        Capability {
+            issuer,
+            params: "\"messaging\"".to_string(),
+        }
+    }
+
+    fn our() -> Address {
+        address("our.os", "app", "pkg", "dev.os")
+    }
+
+    fn system(process: &str) -> Address {
+        address("our.os", process, "distro", "sys")
+    }
+
+    /// Issuer and params, for comparing capability lists.
+    fn described(caps: Vec<Capability>) -> Vec<(String, String)> {
+        caps.into_iter()
+            .map(|cap| (cap.issuer.to_string(), cap.params))
+            .collect()
+    }
+
+    /// Everything a parent might hold, only some of which children may get.
+    fn parent_caps() -> Vec<Capability> {
+        vec![
+            messaging(our()),
+            messaging(system("net")),
+            messaging(system("kv")),
+            messaging(system("timer")),
+            // not needed by either child.
+            messaging(system("vfs")),
+            // the same process on another node.
+            messaging(address("other.os", "net", "distro", "sys")),
+            // another process of ours, or of another package.
+            messaging(address("our.os", "other", "pkg", "dev.os")),
+            messaging(address("our.os", "app", "other", "dev.os")),
+            // not a messaging capability.
+            Capability {
+                issuer: system("kv"),
+                params: "{\"kind\":\"write\",\"db\":\"state\"}".to_string(),
+            },
+            Capability {
+                issuer: our(),
+                params: "\"admin\"".to_string(),
+            },
+        ]
+    }
+
+    #[test]
+    fn publishers_get_parent_net_kv_and_timer() {
+        let caps = minimal_capabilities(&our(), WasmType::Pub, parent_caps());
+        assert_eq!(
+            described(caps),
+            described(vec![
+                messaging(our()),
+                messaging(system("net")),
+                messaging(system("kv")),
+                messaging(system("timer")),
+            ])
+        );
+    }
+
+    #[test]
+    fn subscribers_get_parent_and_net() {
+        let caps = minimal_capabilities(&our(), WasmType::Sub, parent_caps());
+        assert_eq!(
+            described(caps),
+            described(vec![messaging(our()), messaging(system("net"))])
+        );
+    }
+
+    #[test]
+    fn nothing_to_pass_on() {
+        assert!(minimal_capabilities(&our(), WasmType::Pub, vec![]).is_empty());
+        assert!(minimal_capabilities(&our(), WasmType::Sub, vec![]).is_empty());
+    }
+}
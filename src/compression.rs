@@ -0,0 +1,57 @@
+use anyhow::{anyhow, Result};
+use flate2::{read::DeflateDecoder, write::DeflateEncoder};
+use std::io::{Read, Write};
+
+use crate::encryption;
+use crate::kinode::process::pub_::Compression;
+
+/// Header naming the compression a payload was encoded with.
+/// Prefixed, so a `content-encoding` users publish passes through untouched.
+pub const CONTENT_ENCODING_HEADER: &str = "pubsub-content-encoding";
+
+const DEFLATE: &str = "deflate";
+
+/// Compresses a payload as configured, and declares it in the headers.
+/// Payloads that already declare an encoding, or are encrypted, are left alone.
+pub fn compress(
+    compression: Compression,
+    headers: &mut Vec<(String, String)>,
+    bytes: Vec<u8>,
+) -> Result<Vec<u8>> {
+    if content_encoding(headers).is_some() || encryption::is_encrypted(headers) {
+        return Ok(bytes);
+    }
+    match compression {
+        Compression::None => Ok(bytes),
+        Compression::Deflate => {
+            let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(&bytes)?;
+            headers.push((CONTENT_ENCODING_HEADER.to_string(), DEFLATE.to_string()));
+            Ok(encoder.finish()?)
+        }
+    }
+}
+
+/// Decompresses a payload according to its headers, and removes the declaration.
+pub fn decompress(headers: &mut Vec<(String, String)>, bytes: Vec<u8>) -> Result<Vec<u8>> {
+    let Some(encoding) = content_encoding(headers) else {
+        return Ok(bytes);
+    };
+    let decompressed = match encoding.as_str() {
+        DEFLATE => {
+            let mut decompressed = Vec::new();
+            DeflateDecoder::new(bytes.as_slice()).read_to_end(&mut decompressed)?;
+            decompressed
+        }
+        other => return Err(anyhow!("unsupported content-encoding: {}", other)),
+    };
+    headers.retain(|(name, _)| !name.eq_ignore_ascii_case(CONTENT_ENCODING_HEADER));
+    Ok(decompressed)
+}
+
+fn content_encoding(headers: &[(String, String)]) -> Option<String> {
+    headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(CONTENT_ENCODING_HEADER))
+        .map(|(_, value)| value.to_lowercase())
+}
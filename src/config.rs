@@ -0,0 +1,129 @@
+use serde::{Deserialize, Deserializer};
+
+use crate::kinode::process::pub_::{
+    AccessControl, Compression, Persistence, PubConfig, RetentionPolicy,
+};
+
+/// A `PubConfig` as saved by any version so far. Fields added after the first
+/// release are missing from older saves, and get their defaults.
+#[derive(Deserialize)]
+struct SavedPubConfig {
+    max_retry_attempts: u32,
+    retry_interval: u64,
+    heartbeat_interval: u64,
+    #[serde(default = "ack_timeout")]
+    ack_timeout: u64,
+    default_persistence: Persistence,
+    #[serde(default = "retention")]
+    retention: RetentionPolicy,
+    #[serde(default = "compression")]
+    compression: Compression,
+    #[serde(default = "access")]
+    access: AccessControl,
+}
+
+fn ack_timeout() -> u64 {
+    PubConfig::default().ack_timeout
+}
+
+fn retention() -> RetentionPolicy {
+    PubConfig::default().retention
+}
+
+fn compression() -> Compression {
+    PubConfig::default().compression
+}
+
+fn access() -> AccessControl {
+    PubConfig::default().access
+}
+
+impl From<SavedPubConfig> for PubConfig {
+    fn from(saved: SavedPubConfig) -> Self {
+        PubConfig {
+            max_retry_attempts: saved.max_retry_attempts,
+            retry_interval: saved.retry_interval,
+            heartbeat_interval: saved.heartbeat_interval,
+            ack_timeout: saved.ack_timeout,
+            default_persistence: saved.default_persistence,
+            retention: saved.retention,
+            compression: saved.compression,
+            access: saved.access,
+        }
+    }
+}
+
+/// Deserializes a saved `PubConfig`, filling in the fields older versions didn't have.
+/// Use on saved state with `#[serde(deserialize_with = "kinode_pubsub::config::deserialize")]`.
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PubConfig, D::Error> {
+    Ok(SavedPubConfig::deserialize(deserializer)?.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct Saved {
+        #[serde(deserialize_with = "deserialize")]
+        config: PubConfig,
+    }
+
+    #[test]
+    fn loads_config_saved_at_first_release() {
+        let saved = r#"{"config":{
+            "max_retry_attempts":5,
+            "retry_interval":10,
+            "heartbeat_interval":20,
+            "default_persistence":{"Disk":50}
+        }}"#;
+        let config = serde_json::from_str::<Saved>(saved).unwrap().config;
+        assert_eq!(
+            config,
+            PubConfig {
+                max_retry_attempts: 5,
+                retry_interval: 10,
+                heartbeat_interval: 20,
+                default_persistence: Persistence::Disk(50),
+                ..PubConfig::default()
+            }
+        );
+    }
+
+    #[test]
+    fn loads_retention_saved_before_max_bytes() {
+        let saved = r#"{"config":{
+            "max_retry_attempts":3,
+            "retry_interval":120,
+            "heartbeat_interval":60,
+            "ack_timeout":30,
+            "default_persistence":{"Memory":1000},
+            "retention":{"max_age":3600}
+        }}"#;
+        let config = serde_json::from_str::<Saved>(saved).unwrap().config;
+        assert_eq!(
+            config.retention,
+            RetentionPolicy {
+                max_age: Some(3600),
+                max_bytes: None,
+            }
+        );
+    }
+
+    #[test]
+    fn round_trips_current_config() {
+        let config = PubConfig {
+            compression: Compression::Deflate,
+            access: AccessControl::Denylist(vec![]),
+            ..PubConfig::default()
+        };
+        let saved = format!(
+            r#"{{"config":{}}}"#,
+            serde_json::to_string(&config).unwrap()
+        );
+        assert_eq!(
+            serde_json::from_str::<Saved>(&saved).unwrap().config,
+            config
+        );
+    }
+}
@@ -0,0 +1,114 @@
+use anyhow::{anyhow, Result};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+
+use crate::kinode::process::common::TopicKey;
+
+/// Header naming the cipher an encrypted payload was sealed with.
+/// Prefixed, so it can't clash with headers users publish.
+pub const ENCRYPTION_HEADER: &str = "pubsub-encryption";
+/// Header carrying the epoch of the topic key a payload was sealed with.
+pub const KEY_EPOCH_HEADER: &str = "pubsub-key-epoch";
+
+const CHACHA20POLY1305: &str = "chacha20poly1305";
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+
+/// Errors unless the key is usable, i.e. 32 bytes long.
+pub fn validate_key(key: &TopicKey) -> Result<()> {
+    if key.key.len() != KEY_LEN {
+        return Err(anyhow!(
+            "topic key must be {} bytes, got {}",
+            KEY_LEN,
+            key.key.len()
+        ));
+    }
+    Ok(())
+}
+
+/// Whether the headers declare an encrypted payload.
+pub fn is_encrypted(headers: &[(String, String)]) -> bool {
+    header(headers, ENCRYPTION_HEADER).is_some()
+}
+
+/// Encrypts a payload under the topic key, and declares the cipher and key epoch in the headers.
+/// The sealed payload is the nonce followed by the ciphertext, bound to the topic name.
+pub fn encrypt(
+    key: &TopicKey,
+    topic: &str,
+    headers: &mut Vec<(String, String)>,
+    bytes: &[u8],
+) -> Result<Vec<u8>> {
+    validate_key(key)?;
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(&key.key));
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: bytes,
+                aad: topic.as_bytes(),
+            },
+        )
+        .map_err(|_| anyhow!("failed to encrypt payload"))?;
+
+    headers.push((ENCRYPTION_HEADER.to_string(), CHACHA20POLY1305.to_string()));
+    headers.push((KEY_EPOCH_HEADER.to_string(), key.key_epoch.to_string()));
+    let mut sealed = nonce.to_vec();
+    sealed.extend_from_slice(&ciphertext);
+    Ok(sealed)
+}
+
+/// Decrypts a payload with whichever of `keys` has the epoch it was sealed with,
+/// and removes the declaration. Unencrypted payloads are returned as they are.
+pub fn decrypt(
+    keys: &[TopicKey],
+    topic: &str,
+    headers: &mut Vec<(String, String)>,
+    bytes: Vec<u8>,
+) -> Result<Vec<u8>> {
+    let Some(cipher_name) = header(headers, ENCRYPTION_HEADER) else {
+        return Ok(bytes);
+    };
+    if cipher_name != CHACHA20POLY1305 {
+        return Err(anyhow!("unsupported encryption: {}", cipher_name));
+    }
+    let key_epoch: u64 = header(headers, KEY_EPOCH_HEADER)
+        .ok_or(anyhow!("encrypted payload has no key-epoch"))?
+        .parse()?;
+    let key = keys
+        .iter()
+        .find(|key| key.key_epoch == key_epoch)
+        .ok_or(anyhow!("no key for key-epoch {}", key_epoch))?;
+    validate_key(key)?;
+    if bytes.len() < NONCE_LEN {
+        return Err(anyhow!("encrypted payload is too short"));
+    }
+
+    let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(&key.key));
+    let plaintext = cipher
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: topic.as_bytes(),
+            },
+        )
+        .map_err(|_| anyhow!("failed to decrypt payload with key-epoch {}", key_epoch))?;
+
+    headers.retain(|(name, _)| {
+        !name.eq_ignore_ascii_case(ENCRYPTION_HEADER)
+            && !name.eq_ignore_ascii_case(KEY_EPOCH_HEADER)
+    });
+    Ok(plaintext)
+}
+
+fn header(headers: &[(String, String)], name: &str) -> Option<String> {
+    headers
+        .iter()
+        .find(|(header, _)| header.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.to_lowercase())
+}
@@ -1,125 +1,693 @@
-use crate::Persistence;
-use anyhow::Result;
+use crate::{Persistence, RetentionPolicy};
+use anyhow::{anyhow, Result};
 use kinode_process_lib::{
     kv::{self, Kv},
     Address,
 };
 use serde::{Deserialize, Serialize};
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Message {
     pub sequence: u64,
+    /// publish time, in seconds since the unix epoch.
+    #[serde(default)]
+    pub timestamp: u64,
+    /// compaction key, compacted topics keep only the latest message per key.
+    #[serde(default)]
+    pub key: Option<String>,
     pub content: Vec<u8>,
+    #[serde(default)]
+    pub headers: Vec<(String, String)>,
+}
+
+impl Message {
+    /// A message published now, without key or headers.
+    pub fn new(sequence: u64, content: Vec<u8>) -> Self {
+        Message {
+            sequence,
+            timestamp: now(),
+            key: None,
+            content,
+            headers: vec![],
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
 }
 
+/// On-disk record format. New versions get a new variant,
+/// older records keep decoding through theirs.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+enum DiskRecord {
+    V1 {
+        sequence: u64,
+        timestamp: u64,
+        content: Vec<u8>,
+        headers: Vec<(String, String)>,
+    },
+    /// adds the compaction key.
+    V2 {
+        sequence: u64,
+        timestamp: u64,
+        key: Option<String>,
+        content: Vec<u8>,
+        headers: Vec<(String, String)>,
+    },
+}
+
+impl DiskRecord {
+    fn encode(message: &Message) -> Result<Vec<u8>> {
+        let record = DiskRecord::V2 {
+            sequence: message.sequence,
+            timestamp: message.timestamp,
+            key: message.key.clone(),
+            content: message.content.clone(),
+            headers: message.headers.clone(),
+        };
+        Ok(serde_json::to_vec(&record)?)
+    }
+
+    /// Records written before the versioned format hold only the raw content.
+    fn decode(sequence: u64, bytes: Vec<u8>) -> Message {
+        match serde_json::from_slice::<DiskRecord>(&bytes) {
+            Ok(DiskRecord::V1 {
+                sequence,
+                timestamp,
+                content,
+                headers,
+            }) => Message {
+                sequence,
+                timestamp,
+                key: None,
+                content,
+                headers,
+            },
+            Ok(DiskRecord::V2 {
+                sequence,
+                timestamp,
+                key,
+                content,
+                headers,
+            }) => Message {
+                sequence,
+                timestamp,
+                key,
+                content,
+                headers,
+            },
+            Err(_) => Message {
+                sequence,
+                timestamp: 0,
+                key: None,
+                content: bytes,
+                headers: vec![],
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "SavedEntry")]
 enum StorageEntry {
     Full(Message),
+    Indexed(IndexEntry),
+}
+
+/// Entries as they may have been saved, including Disk entries
+/// from before the index kept more than the sequence.
+#[derive(Deserialize)]
+enum SavedEntry {
+    Full(Message),
+    Indexed(IndexEntry),
     SequenceOnly(u64),
 }
 
+impl From<SavedEntry> for StorageEntry {
+    fn from(saved: SavedEntry) -> Self {
+        match saved {
+            SavedEntry::Full(message) => StorageEntry::Full(message),
+            SavedEntry::Indexed(entry) => StorageEntry::Indexed(entry),
+            // filled in from the kv store when the history is rebuilt.
+            SavedEntry::SequenceOnly(sequence) => StorageEntry::Indexed(IndexEntry {
+                sequence,
+                timestamp: 0,
+                size: 0,
+                key: None,
+            }),
+        }
+    }
+}
+
+/// What we keep in memory about a message stored on disk.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct IndexEntry {
+    sequence: u64,
+    timestamp: u64,
+    /// payload size in bytes.
+    size: u64,
+    #[serde(default)]
+    key: Option<String>,
+}
+
+impl StorageEntry {
+    fn indexed(message: &Message) -> Self {
+        StorageEntry::Indexed(IndexEntry {
+            sequence: message.sequence,
+            timestamp: message.timestamp,
+            size: message.content.len() as u64,
+            key: message.key.clone(),
+        })
+    }
+
+    fn key(&self) -> Option<&String> {
+        match self {
+            StorageEntry::Full(msg) => msg.key.as_ref(),
+            StorageEntry::Indexed(entry) => entry.key.as_ref(),
+        }
+    }
+
+    fn sequence(&self) -> u64 {
+        match self {
+            StorageEntry::Full(msg) => msg.sequence,
+            StorageEntry::Indexed(entry) => entry.sequence,
+        }
+    }
+
+    fn timestamp(&self) -> u64 {
+        match self {
+            StorageEntry::Full(msg) => msg.timestamp,
+            StorageEntry::Indexed(entry) => entry.timestamp,
+        }
+    }
+
+    fn size(&self) -> u64 {
+        match self {
+            StorageEntry::Full(msg) => msg.content.len() as u64,
+            StorageEntry::Indexed(entry) => entry.size,
+        }
+    }
+}
+
+/// Sequences start at 1, so key 0 is free to hold the on-disk index.
+const INDEX_KEY: u64 = 0;
+
+/// Sequences never get this high, so the last one handed out is kept here.
+const SEQUENCE_KEY: u64 = u64::MAX;
+
+/// Range of sequences kept on disk, stored alongside them so that
+/// the index can be rebuilt from the kv store alone.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct DiskIndex {
+    first: u64,
+    last: u64,
+    /// highest sequence dropped by retention limits, rather than compacted away.
+    #[serde(default)]
+    evicted_through: u64,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct MessageHistory {
     our: Address,
+    /// per-topic kv database, so topics in the same package don't overwrite each other.
+    #[serde(default = "legacy_db")]
+    db: String,
     entries: VecDeque<StorageEntry>,
     persistence: Persistence,
-    kv: Kv<u64, Vec<u8>>,
+    #[serde(default = "no_retention")]
+    retention: RetentionPolicy,
+    /// running payload total of the stored entries, for max_bytes.
+    #[serde(default)]
+    total_bytes: u64,
+    /// latest sequence per key, for compacted topics.
+    #[serde(default)]
+    keys: HashMap<String, u64>,
+    /// highest sequence dropped by retention limits, rather than compacted away.
+    #[serde(default)]
+    evicted_through: u64,
+    kv: Store,
+}
+
+/// Where Disk messages are kept: the topic's kv database, or a map in tests.
+/// Untagged, so that it's saved exactly as the kv handle alone was.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Store {
+    Kv(Kv<u64, Vec<u8>>),
+    #[cfg(test)]
+    Map(std::cell::RefCell<std::collections::BTreeMap<u64, Vec<u8>>>),
+}
+
+impl Store {
+    fn get(&self, key: &u64) -> Result<Vec<u8>> {
+        match self {
+            Store::Kv(kv) => kv.get(key),
+            #[cfg(test)]
+            Store::Map(map) => map
+                .borrow()
+                .get(key)
+                .cloned()
+                .ok_or(anyhow!("no value at key {}", key)),
+        }
+    }
+
+    fn set(&self, key: &u64, value: &Vec<u8>, timeout: Option<u64>) -> Result<()> {
+        match self {
+            Store::Kv(kv) => kv.set(key, value, timeout),
+            #[cfg(test)]
+            Store::Map(map) => {
+                map.borrow_mut().insert(*key, value.clone());
+                Ok(())
+            }
+        }
+    }
+
+    fn delete(&self, key: &u64, timeout: Option<u64>) -> Result<()> {
+        match self {
+            Store::Kv(kv) => kv.delete(key, timeout),
+            #[cfg(test)]
+            Store::Map(map) => {
+                map.borrow_mut().remove(key);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// state saved before histories were split per topic points at the old shared db.
+fn legacy_db() -> String {
+    "message-history".to_string()
+}
+
+/// state saved before retention policies existed only had the persistence max_length.
+fn no_retention() -> RetentionPolicy {
+    RetentionPolicy {
+        max_age: None,
+        max_bytes: None,
+    }
 }
 
 impl MessageHistory {
-    pub fn new(our: Address, persistence: Persistence) -> Result<Self> {
-        let kv: Kv<u64, Vec<u8>> = kv::open(our.package_id(), "message-history", Some(5))?;
-        Ok(MessageHistory {
+    pub fn new(
+        our: Address,
+        topic: &str,
+        persistence: Persistence,
+        retention: RetentionPolicy,
+    ) -> Result<Self> {
+        let db = format!("message-history-{}", topic);
+        let kv = Store::Kv(kv::open(our.package_id(), &db, Some(5))?);
+        let mut history = MessageHistory {
             our,
+            db,
             entries: VecDeque::new(),
             persistence,
+            retention,
+            total_bytes: 0,
+            keys: HashMap::new(),
+            evicted_through: 0,
             kv,
-        })
+        };
+        history.rebuild_index()?;
+        Ok(history)
     }
 
-    pub fn add_message(&mut self, sequence: u64, content: Vec<u8>) -> Result<()> {
-        let message = Message { sequence, content };
+    /// Rebuilds the Disk index from the kv store, so history survives lost or stale state.
+    /// Also deletes orphaned keys: ones left below the index by failed evictions,
+    /// and ones outside the retention limits if they were tightened since.
+    pub fn rebuild_index(&mut self) -> Result<()> {
+        if !self.on_disk() {
+            return Ok(());
+        }
+        // every topic on the legacy shared db would read the same index,
+        // and sweep each other's messages as orphans.
+        if self.shared_db() {
+            self.fill_entries();
+            return Ok(());
+        }
+        let Some(index) = self.load_index() else {
+            // fresh db, or one written before the index existed.
+            self.fill_entries();
+            return Ok(());
+        };
 
-        match &self.persistence {
-            Persistence::None => {
-                // for ephemeral, we don't need to store anything
+        let mut orphan = index.first;
+        while orphan > INDEX_KEY + 1 {
+            orphan -= 1;
+            if self.kv.get(&orphan).is_err() {
+                break;
             }
-            Persistence::Memory(max_size) => {
-                if self.entries.len() >= *max_size as usize {
-                    self.entries.pop_front();
+            self.kv.delete(&orphan, None)?;
+        }
+
+        self.entries.clear();
+        self.total_bytes = 0;
+        self.keys.clear();
+        self.evicted_through = index.evicted_through;
+        // plain Disk topics keep every message, keyed or not.
+        let compacted = matches!(self.persistence, Persistence::Compacted(_));
+        for sequence in index.first..=index.last {
+            if let Ok(bytes) = self.kv.get(&sequence) {
+                let message = DiskRecord::decode(sequence, bytes);
+                if compacted {
+                    // an interrupted compaction can leave a superseded message behind.
+                    self.compact(message.key.as_ref())?;
+                }
+                self.total_bytes += message.content.len() as u64;
+                self.entries.push_back(StorageEntry::indexed(&message));
+                if let Some(key) = message.key.as_ref().filter(|_| compacted) {
+                    self.keys.insert(key.clone(), sequence);
                 }
-                self.entries.push_back(StorageEntry::Full(message));
             }
-            Persistence::Disk(max_size) => {
-                if self.entries.len() >= *max_size as usize {
-                    if let Some(StorageEntry::SequenceOnly(oldest_seq)) = self.entries.pop_front() {
-                        // delete oldest message
-                        // NOTE: test if this works with a non-existent value!
-                        self.kv.delete(&oldest_seq, None)?;
+        }
+        self.evict(0, 0)?;
+
+        self.save_index()
+    }
+
+    /// Reads what entries saved as bare sequences lack back from the kv store,
+    /// so that retention limits see their real age and size.
+    fn fill_entries(&mut self) {
+        for entry in self.entries.iter_mut() {
+            let StorageEntry::Indexed(IndexEntry { sequence, .. }) = entry else {
+                continue;
+            };
+            let sequence = *sequence;
+            if let Ok(bytes) = self.kv.get(&sequence) {
+                *entry = StorageEntry::indexed(&DiskRecord::decode(sequence, bytes));
+            }
+        }
+        self.total_bytes = self.entries.iter().map(StorageEntry::size).sum();
+    }
+
+    fn on_disk(&self) -> bool {
+        matches!(
+            self.persistence,
+            Persistence::Disk(_) | Persistence::Compacted(_)
+        )
+    }
+
+    fn load_index(&self) -> Option<DiskIndex> {
+        let bytes = self.kv.get(&INDEX_KEY).ok()?;
+        serde_json::from_slice(&bytes).ok()
+    }
+
+    fn save_index(&self) -> Result<()> {
+        if self.shared_db() {
+            return Ok(());
+        }
+        let last = self.get_latest_sequence().unwrap_or(0);
+        let index = DiskIndex {
+            first: self
+                .entries
+                .front()
+                .map_or(last + 1, StorageEntry::sequence),
+            last,
+            evicted_through: self.evicted_through,
+        };
+        self.kv
+            .set(&INDEX_KEY, &serde_json::to_vec(&index)?, None)?;
+        Ok(())
+    }
+
+    /// Errors if the message alone is larger than the topic's byte budget,
+    /// in which case it must not be published at all.
+    pub fn check_fits(&self, size: u64) -> Result<()> {
+        if let Persistence::None = self.persistence {
+            return Ok(());
+        }
+        match self.retention.max_bytes {
+            Some(max_bytes) if size > max_bytes => Err(anyhow!(
+                "message of {} bytes exceeds the topic's history budget of {} bytes",
+                size,
+                max_bytes
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn add_message(&mut self, message: Message) -> Result<()> {
+        self.add_messages(vec![message])
+    }
+
+    /// Stores messages in order, saving the Disk index once for all of them.
+    /// If any message is too large, none are stored.
+    pub fn add_messages(&mut self, messages: Vec<Message>) -> Result<()> {
+        for message in &messages {
+            self.check_fits(message.content.len() as u64)?;
+        }
+
+        for message in messages {
+            let size = message.content.len() as u64;
+            match &self.persistence {
+                Persistence::None => {
+                    // for ephemeral, we don't need to store anything
+                }
+                Persistence::Memory(_) => {
+                    self.evict(1, size)?;
+                    self.entries.push_back(StorageEntry::Full(message));
+                    self.total_bytes += size;
+                }
+                Persistence::Disk(_) => {
+                    self.evict(1, size)?;
+                    self.kv
+                        .set(&message.sequence, &DiskRecord::encode(&message)?, None)?;
+                    self.entries.push_back(StorageEntry::indexed(&message));
+                    self.total_bytes += size;
+                }
+                Persistence::Compacted(_) => {
+                    self.compact(message.key.as_ref())?;
+                    self.evict(1, size)?;
+                    self.kv
+                        .set(&message.sequence, &DiskRecord::encode(&message)?, None)?;
+                    self.entries.push_back(StorageEntry::indexed(&message));
+                    self.total_bytes += size;
+                    if let Some(key) = &message.key {
+                        self.keys.insert(key.clone(), message.sequence);
                     }
                 }
-                self.kv.set(&sequence, &message.content, None)?;
-                self.entries.push_back(StorageEntry::SequenceOnly(sequence));
             }
         }
 
+        if self.on_disk() {
+            self.save_index()?;
+        }
+        Ok(())
+    }
+
+    /// Removes the message a new one with `key` supersedes, if any.
+    /// Messages without a key are never compacted.
+    fn compact(&mut self, key: Option<&String>) -> Result<()> {
+        let Some(superseded) = key.and_then(|key| self.keys.remove(key)) else {
+            return Ok(());
+        };
+        let Some(position) = self
+            .entries
+            .iter()
+            .position(|entry| entry.sequence() == superseded)
+        else {
+            return Ok(());
+        };
+        if let Some(entry) = self.entries.remove(position) {
+            self.total_bytes = self.total_bytes.saturating_sub(entry.size());
+            self.kv.delete(&superseded, None)?;
+        }
+        Ok(())
+    }
+
+    /// First sequence this history still accounts for. Anything before it was
+    /// dropped by retention limits; on compacted topics, sequences after it that
+    /// aren't stored were superseded by a later message with the same key.
+    pub fn first_available_sequence(&self) -> Option<u64> {
+        match self.persistence {
+            Persistence::None => None,
+            Persistence::Compacted(_) => Some(self.evicted_through + 1),
+            Persistence::Memory(_) | Persistence::Disk(_) => {
+                self.entries.front().map(StorageEntry::sequence)
+            }
+        }
+    }
+
+    /// Drops expired messages, called periodically so that age limits hold
+    /// even when nothing new is published.
+    pub fn sweep(&mut self) -> Result<()> {
+        let before = self.entries.len();
+        self.evict(0, 0)?;
+        if self.on_disk() && self.entries.len() != before {
+            self.save_index()?;
+        }
+        Ok(())
+    }
+
+    /// Evicts the oldest entries until the retention limits hold
+    /// with `room` more messages of `room_bytes` in total added.
+    fn evict(&mut self, room: usize, room_bytes: u64) -> Result<()> {
+        let max_size = match self.persistence {
+            Persistence::None => return Ok(()),
+            Persistence::Memory(max_size)
+            | Persistence::Disk(max_size)
+            | Persistence::Compacted(max_size) => max_size as usize,
+        };
+        let cutoff = self
+            .retention
+            .max_age
+            .map(|max_age| now().saturating_sub(max_age));
+
+        while let Some(oldest) = self.entries.front() {
+            // a max_size of 0 leaves the count unbounded.
+            let over_count = max_size > 0 && self.entries.len() + room > max_size;
+            let over_bytes = self
+                .retention
+                .max_bytes
+                .is_some_and(|max_bytes| self.total_bytes + room_bytes > max_bytes);
+            let expired = cutoff.is_some_and(|cutoff| oldest.timestamp() < cutoff);
+            if !over_count && !over_bytes && !expired {
+                break;
+            }
+            let Some(oldest) = self.entries.pop_front() else {
+                break;
+            };
+            self.total_bytes = self.total_bytes.saturating_sub(oldest.size());
+            self.evicted_through = self.evicted_through.max(oldest.sequence());
+            if let Some(key) = oldest.key() {
+                self.keys.remove(key);
+            }
+            if let StorageEntry::Indexed(entry) = oldest {
+                // NOTE: test if this works with a non-existent value!
+                self.kv.delete(&entry.sequence, None)?;
+            }
+        }
         Ok(())
     }
 
     pub fn get_messages_from(&self, start_sequence: u64) -> Result<Vec<Message>> {
-        match &self.persistence {
-            Persistence::None => Ok(vec![]),
-            Persistence::Memory(_) => Ok(self
-                .entries
-                .iter()
-                .filter_map(|entry| {
-                    if let StorageEntry::Full(msg) = entry {
-                        if msg.sequence >= start_sequence {
-                            Some(msg.clone())
-                        } else {
-                            None
-                        }
-                    } else {
-                        None
-                    }
-                })
-                .collect()),
-            Persistence::Disk(_) => {
-                let mut result = Vec::new();
-                for entry in &self.entries {
-                    if let StorageEntry::SequenceOnly(seq) = entry {
-                        if *seq >= start_sequence {
-                            if let Ok(stored_message) = self.kv.get(seq) {
-                                result.push(serde_json::from_slice(&stored_message)?);
-                            }
-                        }
+        let (messages, _) = self.get_range(start_sequence, u64::MAX, usize::MAX)?;
+        Ok(messages)
+    }
+
+    /// Returns up to `limit` messages with sequences in `start_sequence..=end_sequence`,
+    /// oldest first, and the sequence to continue from if more remain in the range.
+    pub fn get_range(
+        &self,
+        start_sequence: u64,
+        end_sequence: u64,
+        limit: usize,
+    ) -> Result<(Vec<Message>, Option<u64>)> {
+        let mut in_range = self
+            .entries
+            .iter()
+            .filter(|entry| (start_sequence..=end_sequence).contains(&entry.sequence()));
+
+        let mut result = Vec::new();
+        for entry in in_range.by_ref().take(limit) {
+            match entry {
+                StorageEntry::Full(msg) => result.push(msg.clone()),
+                StorageEntry::Indexed(IndexEntry { sequence, .. }) => {
+                    if let Ok(stored_message) = self.kv.get(sequence) {
+                        result.push(DiskRecord::decode(*sequence, stored_message));
                     }
                 }
-                Ok(result)
             }
         }
+        let next_cursor = in_range.next().map(StorageEntry::sequence);
+
+        Ok((result, next_cursor))
     }
 
+    /// Latest sequence this history has stored, including one since dropped by
+    /// retention limits, so that the publisher never hands out a sequence twice.
     pub fn get_latest_sequence(&self) -> Option<u64> {
-        self.entries.back().map(|entry| match entry {
-            StorageEntry::Full(msg) => msg.sequence,
-            StorageEntry::SequenceOnly(seq) => *seq,
-        })
+        self.entries
+            .back()
+            .map(StorageEntry::sequence)
+            .or((self.evicted_through > 0).then_some(self.evicted_through))
+    }
+
+    /// Records the last sequence handed out, on its own key, so that it's durable without
+    /// saving the whole publisher state on every publish. Kept for every persistence type.
+    ///
+    /// Returns `false` if the history is still on the legacy shared db, where topics would
+    /// overwrite each other's sequence, and the caller has to persist it some other way.
+    pub fn save_sequence(&self, sequence: u64) -> Result<bool> {
+        if self.shared_db() {
+            return Ok(false);
+        }
+        self.kv
+            .set(&SEQUENCE_KEY, &serde_json::to_vec(&sequence)?, None)?;
+        Ok(true)
+    }
+
+    /// Last sequence recorded with `save_sequence`, if any.
+    pub fn saved_sequence(&self) -> Option<u64> {
+        if self.shared_db() {
+            return None;
+        }
+        let bytes = self.kv.get(&SEQUENCE_KEY).ok()?;
+        serde_json::from_slice(&bytes).ok()
     }
 
     pub fn clear(&mut self) -> Result<()> {
         self.entries.clear();
-        if let Persistence::Disk(_) = self.persistence {
+        self.total_bytes = 0;
+        self.keys.clear();
+        self.evicted_through = 0;
+        // the legacy db is shared by every topic in the package, never drop it.
+        if self.on_disk() && !self.shared_db() {
             // Clear all stored messages in KV store
-            kv::remove_db(self.our.package_id(), "message-history", None)?;
+            kv::remove_db(self.our.package_id(), &self.db, None)?;
             // see if this works with a non-existent db
-            let kv: Kv<u64, Vec<u8>> = kv::open(self.our.package_id(), "message-history", None)?;
-            self.kv = kv;
+            self.kv = Store::Kv(kv::open(self.our.package_id(), &self.db, None)?);
+        }
+        Ok(())
+    }
+
+    /// Removes this topic's history for good, including its kv database.
+    /// Other topics in the package keep theirs: a topic still on the legacy
+    /// shared db leaves it in place.
+    pub fn destroy(&mut self) -> Result<()> {
+        self.entries.clear();
+        self.total_bytes = 0;
+        self.keys.clear();
+        if !self.shared_db() {
+            kv::remove_db(self.our.package_id(), &self.db, None)?;
         }
         Ok(())
     }
 
+    /// Moves a history still on the legacy shared db into the topic's own db.
+    /// The messages it indexes are copied over, the shared db is left as it is
+    /// for the other topics still on it.
+    pub fn migrate_shared_db(&mut self, topic: &str) -> Result<()> {
+        if !self.shared_db() {
+            return Ok(());
+        }
+        let db = format!("message-history-{}", topic);
+        let kv = Store::Kv(kv::open(self.our.package_id(), &db, Some(5))?);
+        for entry in &self.entries {
+            if let StorageEntry::Indexed(IndexEntry { sequence, .. }) = entry {
+                if let Ok(bytes) = self.kv.get(sequence) {
+                    kv.set(sequence, &bytes, None)?;
+                }
+            }
+        }
+        self.db = db;
+        self.kv = kv;
+        if self.on_disk() {
+            self.save_index()?;
+        }
+        Ok(())
+    }
+
+    /// Whether this history still lives in the db shared by all topics of the package.
+    fn shared_db(&self) -> bool {
+        self.db == legacy_db()
+    }
+
     pub fn len(&self) -> usize {
         self.entries.len()
     }
@@ -128,7 +696,193 @@ impl MessageHistory {
         self.entries.is_empty()
     }
 
+    /// Total payload bytes currently stored.
+    pub fn total_bytes(&self) -> u64 {
+        self.total_bytes
+    }
+
     pub fn persistence_type(&self) -> &Persistence {
         &self.persistence
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn full_message() -> Message {
+        Message {
+            sequence: 7,
+            timestamp: 1_700_000_000,
+            key: Some("user-1".to_string()),
+            content: b"{\"name\": \"alice\"}".to_vec(),
+            headers: vec![
+                ("content-type".to_string(), "application/json".to_string()),
+                ("trace".to_string(), "abc".to_string()),
+            ],
+        }
+    }
+
+    #[test]
+    fn disk_record_round_trips_full_message() {
+        let message = full_message();
+        let bytes = DiskRecord::encode(&message).unwrap();
+        assert_eq!(DiskRecord::decode(message.sequence, bytes), message);
+    }
+
+    /// A history on a map instead of a kv database.
+    fn history(persistence: Persistence) -> MessageHistory {
+        MessageHistory {
+            our: Address::new(
+                "our.os",
+                kinode_process_lib::ProcessId::new(Some("app"), "pkg", "dev.os"),
+            ),
+            db: "message-history-test".to_string(),
+            entries: VecDeque::new(),
+            persistence,
+            retention: no_retention(),
+            total_bytes: 0,
+            keys: HashMap::new(),
+            evicted_through: 0,
+            kv: Store::Map(Default::default()),
+        }
+    }
+
+    fn messages() -> Vec<Message> {
+        (1..=5)
+            .map(|sequence| Message {
+                sequence,
+                timestamp: 1_700_000_000 + sequence,
+                key: (sequence % 2 == 0).then(|| format!("key-{}", sequence)),
+                content: format!("message {}", sequence).into_bytes(),
+                headers: vec![("n".to_string(), sequence.to_string())],
+            })
+            .collect()
+    }
+
+    #[test]
+    fn disk_and_memory_replay_the_same_messages() {
+        let mut memory = history(Persistence::Memory(10));
+        let mut disk = history(Persistence::Disk(10));
+        for message in messages() {
+            memory.add_message(message.clone()).unwrap();
+            disk.add_message(message).unwrap();
+        }
+
+        assert_eq!(memory.get_messages_from(1).unwrap(), messages());
+        assert_eq!(disk.get_messages_from(1).unwrap(), messages());
+        assert_eq!(
+            memory.get_messages_from(3).unwrap(),
+            disk.get_messages_from(3).unwrap()
+        );
+        assert_eq!(
+            memory.get_range(2, 4, 2).unwrap(),
+            disk.get_range(2, 4, 2).unwrap()
+        );
+        assert_eq!(disk.get_range(2, 4, 2).unwrap().1, Some(4));
+        assert_eq!(memory.total_bytes(), disk.total_bytes());
+    }
+
+    #[test]
+    fn disk_and_memory_evict_the_same_messages() {
+        let mut memory = history(Persistence::Memory(3));
+        let mut disk = history(Persistence::Disk(3));
+        memory.add_messages(messages()).unwrap();
+        disk.add_messages(messages()).unwrap();
+
+        assert_eq!(memory.get_messages_from(1).unwrap(), messages()[2..]);
+        assert_eq!(disk.get_messages_from(1).unwrap(), messages()[2..]);
+        assert_eq!(memory.first_available_sequence(), Some(3));
+        assert_eq!(disk.first_available_sequence(), Some(3));
+        // evicted messages are gone from the store too.
+        assert!(disk.kv.get(&1).is_err());
+    }
+
+    #[test]
+    fn disk_history_survives_a_rebuild() {
+        let mut disk = history(Persistence::Disk(10));
+        disk.add_messages(messages()).unwrap();
+        disk.entries.clear();
+        disk.rebuild_index().unwrap();
+
+        assert_eq!(disk.get_messages_from(1).unwrap(), messages());
+        assert_eq!(disk.get_latest_sequence(), Some(5));
+    }
+
+    #[test]
+    fn saved_sequence_survives_a_rebuild() {
+        let mut disk = history(Persistence::Disk(10));
+        disk.add_messages(messages()).unwrap();
+        assert!(disk.save_sequence(5).unwrap());
+        disk.entries.clear();
+        disk.rebuild_index().unwrap();
+
+        assert_eq!(disk.saved_sequence(), Some(5));
+        assert_eq!(disk.get_messages_from(1).unwrap(), messages());
+    }
+
+    #[test]
+    fn shared_db_keeps_no_sequence() {
+        let mut memory = history(Persistence::Memory(10));
+        memory.db = legacy_db();
+        assert!(!memory.save_sequence(5).unwrap());
+        assert_eq!(memory.saved_sequence(), None);
+    }
+
+    #[test]
+    fn disk_record_decodes_v1() {
+        let record = DiskRecord::V1 {
+            sequence: 3,
+            timestamp: 1_600_000_000,
+            content: b"hello".to_vec(),
+            headers: vec![("content-type".to_string(), "text/plain".to_string())],
+        };
+        let bytes = serde_json::to_vec(&record).unwrap();
+        assert_eq!(
+            DiskRecord::decode(3, bytes),
+            Message {
+                sequence: 3,
+                timestamp: 1_600_000_000,
+                key: None,
+                content: b"hello".to_vec(),
+                headers: vec![("content-type".to_string(), "text/plain".to_string())],
+            }
+        );
+    }
+
+    #[test]
+    fn disk_record_falls_back_to_raw_content() {
+        let bytes = b"raw payload from before records".to_vec();
+        assert_eq!(
+            DiskRecord::decode(5, bytes.clone()),
+            Message {
+                sequence: 5,
+                timestamp: 0,
+                key: None,
+                content: bytes,
+                headers: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn storage_entry_loads_sequence_only() {
+        let entry: StorageEntry = serde_json::from_str("{\"SequenceOnly\":4}").unwrap();
+        assert_eq!(entry.sequence(), 4);
+        assert_eq!(entry.size(), 0);
+        assert!(matches!(entry, StorageEntry::Indexed(_)));
+    }
+
+    #[test]
+    fn disk_record_keeps_empty_key_and_headers() {
+        let message = Message {
+            sequence: 1,
+            timestamp: 42,
+            key: None,
+            content: vec![],
+            headers: vec![],
+        };
+        let bytes = DiskRecord::encode(&message).unwrap();
+        assert_eq!(DiskRecord::decode(1, bytes), message);
+    }
+}
@@ -6,19 +6,32 @@ wit_bindgen::generate!({
 });
 
 mod binary_helpers;
+mod capabilities;
+pub mod compression;
+pub mod config;
+pub mod encryption;
 pub mod history;
 pub mod pubsub;
+pub mod typed;
 
 // re-export main api helper structs
 
 pub use history::MessageHistory;
-pub use pubsub::{Pub, PubError, Sub, SubError};
+pub use pubsub::{DroppedSubscription, Pub, PubError, PublisherDrift, Sub, SubError};
+pub use typed::{Codec, Json, Received, Topic};
 
 // re-export common wit types
-pub use kinode::process::common::UnsubscribeRequest;
+pub use kinode::process::common::{
+    AckRequest, BackfillRequest, BackfillResponse, Heartbeat, SubscribeRejection, TopicKey,
+    UnsubscribeRequest,
+};
 pub use kinode::process::pub_::{
-    InitPubRequest, Persistence, PubConfig, PubRequest, PublishRequest,
+    AccessControl, Compression, FetchHistoryRequest, FetchHistoryResponse, HistoryMessage,
+    InitPubRequest, Persistence, Principal, PubConfig, PubEvent, PubRequest, PubResponse,
+    PublishBatchRequest, PublishRequest, PublishResponse, PublisherStatus, RetentionPolicy,
+    SubscriberDroppedEvent,
 };
 pub use kinode::process::sub::{
-    InitSubRequest, SubRequest, SubResponse, SubscribeRequest, SubscribeResponse,
+    GapEvent, InitSubRequest, SubRequest, SubResponse, SubscribeRequest, SubscribeResponse,
+    TopicClosedEvent, TopicResetEvent,
 };
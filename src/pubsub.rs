@@ -1,21 +1,36 @@
 use anyhow::Result;
+use kinode_process_lib::kinode::process::standard::SpawnError;
 use kinode_process_lib::kv::Kv;
-use kinode_process_lib::{
-    kv, our_capabilities, spawn, Address, OnExit, PackageId, ProcessId, Request,
-};
+use kinode_process_lib::{kv, spawn, Address, OnExit, PackageId, ProcessId, Request};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::time::{Duration, Instant};
 
 use crate::binary_helpers::{populate_wasm, WasmType};
+use crate::capabilities::child_capabilities;
+use crate::{compression, config, encryption};
 
-use crate::kinode::process::common::UnsubscribeRequest;
+use crate::kinode::process::common::{SubscribeRejection, TopicKey, UnsubscribeRequest};
 use crate::kinode::process::pub_::{
-    InitPubRequest, Persistence, PubConfig, PubRequest, PublishRequest,
+    AccessControl, Compression, FetchHistoryRequest, FetchHistoryResponse, InitPubRequest,
+    Persistence, PubConfig, PubRequest, PubResponse, PublishBatchRequest, PublishRequest,
+    PublishResponse, PublisherStatus, RetentionPolicy,
 };
 use crate::kinode::process::sub::{
     InitSubRequest, SubRequest, SubscribeRequest, SubscribeResponse,
 };
 
+/// Seconds to wait on a subscriber process for the publisher's answer to a subscribe,
+/// longer than the subscriber process itself waits on the publisher.
+const SUBSCRIBE_TIMEOUT: u64 = 15;
+
+/// Seconds `Sub::reconcile` may spend in total waiting on subscriber processes,
+/// so that loading a `Sub` stays quick however many of them hang.
+const RECONCILE_TIMEOUT: u64 = 10;
+
+/// Headers starting with this are set by the library, and can't be published.
+const RESERVED_HEADER_PREFIX: &str = "pubsub-";
+
 /// Represents a publisher in the pub-sub system.
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[allow(unused)]
@@ -23,14 +38,40 @@ pub struct Pub {
     publishers: HashMap<String, Publisher>,
     our: Address,
     kv: Kv<String, Vec<u8>>,
+    #[serde(deserialize_with = "config::deserialize")]
     default_config: PubConfig,
+    /// what the last reconcile found, not persisted.
+    #[serde(skip)]
+    drift: Vec<PublisherDrift>,
 }
 
 /// Metadata for a specific publisher.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Publisher {
     pub address: Address,
+    #[serde(deserialize_with = "config::deserialize")]
     pub config: PubConfig,
+    /// current key of an encrypted topic.
+    #[serde(default)]
+    pub key: Option<TopicKey>,
+}
+
+/// A difference between a stored publisher and its running process, see `Pub::reconcile`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum PublisherDrift {
+    /// The process was gone, and was spawned again with the stored config.
+    Respawned { topic: String },
+    /// The process was gone, and spawning it again failed.
+    RespawnFailed { topic: String, error: String },
+    /// The process exists, but didn't answer the status check,
+    /// e.g. a publisher built before status checks existed.
+    Unresponsive { topic: String },
+    /// The process runs with a different config than the stored one.
+    ConfigMismatch {
+        topic: String,
+        stored: PubConfig,
+        running: PubConfig,
+    },
 }
 
 #[allow(unused)]
@@ -53,13 +94,18 @@ impl Pub {
 
         // load state
         let pub_instance = match Self::load_state(&kv) {
-            Ok(loaded_state) => loaded_state,
+            Ok(mut loaded_state) => {
+                // a reboot or reinstall can leave publishers missing or out of date.
+                loaded_state.reconcile();
+                loaded_state
+            }
             Err(_) => {
                 let new_state = Self {
                     publishers: HashMap::new(),
                     our: our.clone(),
                     kv: kv.clone(),
                     default_config,
+                    drift: vec![],
                 };
                 new_state.save_state()?;
                 new_state
@@ -95,7 +141,62 @@ impl Pub {
         Ok(())
     }
 
+    /// Checks every known publisher against its running process. Publishers whose
+    /// process is gone are spawned again with their stored config, a process running
+    /// with a different config is reported but left as it is.
+    /// Called when a `Pub` is loaded, see `drift` for what that found.
+    ///
+    /// # Returns
+    ///
+    /// The drift found, empty if every publisher matched its stored state.
+    pub fn reconcile(&mut self) -> Vec<PublisherDrift> {
+        let mut drift = vec![];
+        let topics: Vec<String> = self.publishers.keys().cloned().collect();
+        for topic in topics {
+            let Some(publisher) = self.publishers.get(&topic) else {
+                continue;
+            };
+            if let Some(status) = publisher_status(&publisher.address) {
+                if status.config != publisher.config {
+                    drift.push(PublisherDrift::ConfigMismatch {
+                        topic,
+                        stored: publisher.config.clone(),
+                        running: status.config,
+                    });
+                }
+                continue;
+            }
+
+            let config = publisher.config.clone();
+            match self.spawn_publisher(&topic, &config) {
+                Ok(address) => {
+                    if let Some(publisher) = self.publishers.get_mut(&topic) {
+                        publisher.address = address;
+                    }
+                    drift.push(PublisherDrift::Respawned { topic });
+                }
+                Err(SpawnError::NameTaken) => {
+                    drift.push(PublisherDrift::Unresponsive { topic });
+                }
+                Err(e) => drift.push(PublisherDrift::RespawnFailed {
+                    topic,
+                    error: e.to_string(),
+                }),
+            }
+        }
+
+        let _ = self.save_state();
+        self.drift = drift.clone();
+        drift
+    }
+
+    /// What the last `reconcile` found, including the one run when this `Pub` was loaded.
+    pub fn drift(&self) -> &[PublisherDrift] {
+        &self.drift
+    }
+
     /// Creates a new topic with the given configuration or uses the default.
+    /// If the topic exists and its publisher is running, nothing is spawned.
     ///
     /// # Arguments
     ///
@@ -104,12 +205,43 @@ impl Pub {
     ///
     /// # Returns
     ///
-    /// A `Result` indicating success or a `PubError`.
+    /// A `Result` indicating success or a `PubError`. Fails with `TopicExists`
+    /// if the topic is running with a different config than the one given.
     pub fn new_topic(&mut self, topic: &str, config: Option<PubConfig>) -> Result<(), PubError> {
-        // spawn new publisher process
+        if let Some(publisher) = self.publishers.get(topic) {
+            if publisher_status(&publisher.address).is_some() {
+                return match config {
+                    Some(config) if config != publisher.config => {
+                        Err(PubError::TopicExists(topic.to_string()))
+                    }
+                    _ => Ok(()),
+                };
+            }
+        }
+
+        let config = config.unwrap_or(self.default_config.clone());
+        let publisher_address = match self.spawn_publisher(topic, &config) {
+            Ok(address) => address,
+            // running, but built before status checks: keep it as it is.
+            Err(SpawnError::NameTaken) if self.publishers.contains_key(topic) => return Ok(()),
+            Err(e) => return Err(PubError::SpawningError(e.to_string())),
+        };
+
+        let key = self.publishers.get(topic).and_then(|p| p.key.clone());
+        let publisher = Publisher {
+            address: publisher_address,
+            config: config,
+            key,
+        };
+
+        self.publishers.insert(topic.to_string(), publisher);
+        let _ = self.save_state();
+        Ok(())
+    }
 
-        // TODO: implement more granular capabilities, not just passing all from parent.
-        let our_caps = our_capabilities();
+    /// Spawns the publisher process for a topic, and sends it its config.
+    fn spawn_publisher(&self, topic: &str, config: &PubConfig) -> Result<Address, SpawnError> {
+        let our_caps = child_capabilities(&self.our, WasmType::Pub);
         let process_name = format!("pub-{}", topic);
         let wasm_path = format!("{}/pkg/pub.wasm", self.our.package_id());
         let process = spawn(
@@ -119,29 +251,20 @@ impl Pub {
             our_caps,
             vec![],
             true,
-        )
-        .map_err(|e| PubError::SpawningError(e.to_string()))?;
+        )?;
         let publisher_address = Address::new(self.our.node.clone(), process);
 
-        let config = config.unwrap_or(self.default_config.clone());
-
         // send pub info to new process
         let init_pub_request = InitPubRequest {
             topic: topic.to_string(),
-            config: config,
+            config: config.clone(),
         };
         Request::to(&publisher_address)
             .body(&init_pub_request)
             .send()
             .unwrap();
 
-        let publisher = Publisher {
-            address: publisher_address,
-            config: config,
-        };
-
-        self.publishers.insert(topic.to_string(), publisher);
-        Ok(())
+        Ok(publisher_address)
     }
 
     /// Retrieves the publisher for a given topic.
@@ -168,10 +291,70 @@ impl Pub {
     ///
     /// A `Result` indicating success or a `PubError`.
     pub fn publish(&mut self, topic: &str, message: &[u8]) -> Result<(), PubError> {
+        self.publish_message(topic, None, vec![], message)
+    }
+
+    /// Publishes a keyed message to a specific topic, creating the topic if it doesn't exist.
+    /// On topics with `Persistence::Compacted`, history keeps only the latest message per key.
+    ///
+    /// # Arguments
+    ///
+    /// * `topic` - The name of the topic to publish to.
+    /// * `key` - The key of the entity this message updates.
+    /// * `message` - The message to publish.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or a `PubError`.
+    pub fn publish_keyed(
+        &mut self,
+        topic: &str,
+        key: &str,
+        message: &[u8],
+    ) -> Result<(), PubError> {
+        self.publish_message(topic, Some(key), vec![], message)
+    }
+
+    /// Publishes a message with headers to a specific topic, creating the topic if it doesn't exist.
+    /// Headers are stored in history and reach subscribers unchanged. Names starting with
+    /// `pubsub-` are reserved for the library and get a `PubError::PublishError`.
+    ///
+    /// # Arguments
+    ///
+    /// * `topic` - The name of the topic to publish to.
+    /// * `headers` - Key/value metadata, e.g. `("content-type", "application/json")`.
+    /// * `message` - The message to publish.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or a `PubError`.
+    pub fn publish_with_headers(
+        &mut self,
+        topic: &str,
+        headers: Vec<(String, String)>,
+        message: &[u8],
+    ) -> Result<(), PubError> {
+        self.publish_message(topic, None, headers, message)
+    }
+
+    pub(crate) fn publish_message(
+        &mut self,
+        topic: &str,
+        key: Option<&str>,
+        headers: Vec<(String, String)>,
+        message: &[u8],
+    ) -> Result<(), PubError> {
         if let Some(publisher) = self.publishers.get(topic) {
+            let mut headers = headers;
+            let message = seal(publisher, topic, &mut headers, message)?;
+            check_message_size(&publisher.config, &message)?;
+
             let publish_message = PubRequest::Publish(PublishRequest {
                 topic: topic.to_string(),
                 sequence: 0,
+                epoch: 0,
+                key: key.map(|k| k.to_string()),
+                headers,
             });
 
             Request::to(&publisher.address)
@@ -186,13 +369,171 @@ impl Pub {
             // leads to default config... which might not be what you want.
             // default config could also be stored and set in the api!
             self.new_topic(topic, None)?;
-            self.publish(topic, message)?;
+            self.publish_message(topic, key, headers, message)?;
             // NOTE: this could be a topic.publish instead! to avoid infinite loops or something..?
             Ok(())
         }
     }
 
-    /// Removes a topic and its associated publisher.
+    /// Publishes several messages to a topic in one request, creating the topic if it doesn't exist.
+    /// They get consecutive sequence numbers, and each subscriber receives them in one delivery.
+    ///
+    /// # Arguments
+    ///
+    /// * `topic` - The name of the topic to publish to.
+    /// * `messages` - The messages to publish, in order.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or a `PubError`.
+    pub fn publish_batch(&mut self, topic: &str, messages: Vec<Vec<u8>>) -> Result<(), PubError> {
+        if messages.is_empty() {
+            return Ok(());
+        }
+        if !self.publishers.contains_key(topic) {
+            self.new_topic(topic, None)?;
+        }
+        let publisher = self.publishers.get(topic).ok_or(PubError::TopicNotFound)?;
+        // every message gets the same headers, the batch shares them.
+        let mut headers = vec![];
+        let mut sealed = Vec::with_capacity(messages.len());
+        for message in &messages {
+            headers = vec![];
+            let message = seal(publisher, topic, &mut headers, message)?;
+            check_message_size(&publisher.config, &message)?;
+            sealed.push(message);
+        }
+        let messages = sealed;
+
+        let batch = PubRequest::PublishBatch(PublishBatchRequest {
+            topic: topic.to_string(),
+            first_sequence: 0,
+            epoch: 0,
+            sizes: messages.iter().map(|m| m.len() as u64).collect(),
+            headers,
+        });
+
+        Request::to(&publisher.address)
+            .body(&batch)
+            .blob_bytes(messages.concat())
+            .send()
+            .unwrap();
+        Ok(())
+    }
+
+    /// Publishes a message and waits for the publisher to assign it a sequence,
+    /// creating the topic if it doesn't exist.
+    ///
+    /// # Arguments
+    ///
+    /// * `topic` - The name of the topic to publish to.
+    /// * `key` - The optional key of the entity this message updates, see `publish_keyed`.
+    /// * `headers` - Key/value metadata, see `publish_with_headers`.
+    /// * `message` - The message to publish.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the assigned sequence, epoch and whether the message was stored, or a `PubError`.
+    pub fn publish_and_await(
+        &mut self,
+        topic: &str,
+        key: Option<&str>,
+        headers: Vec<(String, String)>,
+        message: &[u8],
+    ) -> Result<PublishResponse, PubError> {
+        if !self.publishers.contains_key(topic) {
+            self.new_topic(topic, None)?;
+        }
+        let publisher = self.publishers.get(topic).ok_or(PubError::TopicNotFound)?;
+        let mut headers = headers;
+        let message = seal(publisher, topic, &mut headers, message)?;
+        check_message_size(&publisher.config, &message)?;
+
+        let publish_message = PubRequest::Publish(PublishRequest {
+            topic: topic.to_string(),
+            sequence: 0,
+            epoch: 0,
+            key: key.map(|k| k.to_string()),
+            headers,
+        });
+
+        let res = Request::to(&publisher.address)
+            .body(&publish_message)
+            .blob_bytes(message)
+            .send_and_await_response(10)
+            .map_err(|e| PubError::PublishError(e.to_string()))?
+            .map_err(|e| PubError::PublishError(e.to_string()))?;
+
+        match serde_json::from_slice::<PubResponse>(res.body()) {
+            Ok(PubResponse::Publish(res)) => match res.error {
+                Some(error) => Err(PubError::PublishError(error)),
+                None => Ok(res),
+            },
+            Ok(_) => Err(PubError::PublishError(
+                "unexpected response from publisher".to_string(),
+            )),
+            Err(e) => Err(PubError::PublishError(e.to_string())),
+        }
+    }
+
+    /// Changes who may subscribe to a topic, and read its history.
+    /// Current subscribers that lose access are dropped by the publisher, and get a
+    /// `SubRequest::TopicClosed` as if the topic was removed: their subscriber processes exit.
+    ///
+    /// # Arguments
+    ///
+    /// * `topic` - The name of the topic.
+    /// * `access` - The new access control.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or a `PubError`.
+    pub fn set_access(&mut self, topic: &str, access: AccessControl) -> Result<(), PubError> {
+        let publisher = self
+            .publishers
+            .get_mut(topic)
+            .ok_or(PubError::TopicNotFound)?;
+        publisher.config.access = access.clone();
+
+        let req = PubRequest::SetAccess(access);
+        Request::to(&publisher.address).body(&req).send().unwrap();
+        let _ = self.save_state();
+        Ok(())
+    }
+
+    /// Sets or rotates the key of an encrypted topic. From now on, payloads are encrypted
+    /// before they leave this process, so neither the network nor the publisher's history
+    /// sees them in plaintext. Subscribers need the key, see `Sub::set_topic_key`.
+    ///
+    /// # Arguments
+    ///
+    /// * `topic` - The name of the topic.
+    /// * `key` - The new key, with a `key_epoch` higher than the previous one's.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or a `PubError`.
+    pub fn set_topic_key(&mut self, topic: &str, key: TopicKey) -> Result<(), PubError> {
+        encryption::validate_key(&key).map_err(|e| PubError::InvalidKey(e.to_string()))?;
+        let publisher = self
+            .publishers
+            .get_mut(topic)
+            .ok_or(PubError::TopicNotFound)?;
+        if let Some(current) = &publisher.key {
+            if key.key_epoch <= current.key_epoch {
+                return Err(PubError::InvalidKey(format!(
+                    "key-epoch {} is not newer than the current {}",
+                    key.key_epoch, current.key_epoch
+                )));
+            }
+        }
+        publisher.key = Some(key);
+        let _ = self.save_state();
+        Ok(())
+    }
+
+    /// Removes a topic and its associated publisher, deleting its history.
+    /// Subscribers get a `SubRequest::TopicClosed`, and their subscriber processes exit.
     ///
     /// # Arguments
     ///
@@ -202,14 +543,75 @@ impl Pub {
     ///
     /// A `Result` indicating success or a `PubError`.
     pub fn remove_topic(&mut self, topic: &str) -> Result<(), PubError> {
-        if let Some(publisher) = self.publishers.get(topic) {
+        if let Some(publisher) = self.publishers.remove(topic) {
+            // the publisher tells its subscribers the topic is closed, then exits.
             let req = PubRequest::Kill;
             Request::to(&publisher.address).body(&req).send().unwrap();
+            let _ = self.save_state();
         }
         Ok(())
     }
 }
 
+/// Asks a publisher process for its status, `None` if it doesn't answer.
+fn publisher_status(address: &Address) -> Option<PublisherStatus> {
+    let res = Request::to(address)
+        .body(&PubRequest::Status)
+        .send_and_await_response(5)
+        .ok()?
+        .ok()?;
+    match serde_json::from_slice::<PubResponse>(res.body()) {
+        Ok(PubResponse::Status(status)) => Some(status),
+        _ => None,
+    }
+}
+
+/// Compresses a payload as the topic is configured, so that its size can be checked
+/// before it's sent, and encrypts it if the topic has a key. Ciphertext doesn't
+/// compress, so compressing comes first.
+fn seal(
+    publisher: &Publisher,
+    topic: &str,
+    headers: &mut Vec<(String, String)>,
+    message: &[u8],
+) -> Result<Vec<u8>, PubError> {
+    check_headers(headers)?;
+    let compressed = compression::compress(publisher.config.compression, headers, message.to_vec())
+        .map_err(|e| PubError::EncodeError(e.to_string()))?;
+    let Some(key) = &publisher.key else {
+        return Ok(compressed);
+    };
+    encryption::encrypt(key, topic, headers, &compressed)
+        .map_err(|e| PubError::EncryptionError(e.to_string()))
+}
+
+/// Rejects caller headers in the `pubsub-` namespace, which subscribers read as
+/// instructions for decoding the payload.
+fn check_headers(headers: &[(String, String)]) -> Result<(), PubError> {
+    match headers.iter().find(|(name, _)| {
+        name.to_ascii_lowercase()
+            .starts_with(RESERVED_HEADER_PREFIX)
+    }) {
+        Some((name, _)) => Err(PubError::PublishError(format!("reserved header: {}", name))),
+        None => Ok(()),
+    }
+}
+
+/// Rejects messages that could never fit in the topic's history byte budget.
+/// Checked on the sealed payload, which is what history stores.
+fn check_message_size(config: &PubConfig, message: &[u8]) -> Result<(), PubError> {
+    if let Persistence::None = config.default_persistence {
+        return Ok(());
+    }
+    match config.retention.max_bytes {
+        Some(max_bytes) if message.len() as u64 > max_bytes => Err(PubError::MessageTooLarge {
+            size: message.len() as u64,
+            max_bytes,
+        }),
+        _ => Ok(()),
+    }
+}
+
 /// Default implementation for PubConfig.
 impl Default for PubConfig {
     fn default() -> Self {
@@ -217,7 +619,14 @@ impl Default for PubConfig {
             max_retry_attempts: 3,
             retry_interval: 120,
             heartbeat_interval: 60,
+            ack_timeout: 30,
             default_persistence: Persistence::Memory(1000),
+            retention: RetentionPolicy {
+                max_age: None,
+                max_bytes: None,
+            },
+            compression: Compression::None,
+            access: AccessControl::Public,
         }
     }
 }
@@ -226,9 +635,56 @@ impl Default for PubConfig {
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[allow(unused)]
 pub struct Sub {
+    #[serde(with = "subscription_list")]
     subscriptions: HashMap<Subscription, Subscriber>,
     our: Address,
     kv: Kv<String, Vec<u8>>,
+    /// keys of encrypted topics, every key-epoch we've been given.
+    #[serde(default)]
+    keys: HashMap<String, Vec<TopicKey>>,
+    /// every subscriber process we've spawned and not yet retired,
+    /// including ones whose subscription never completed.
+    #[serde(default)]
+    children: Vec<SpawnedSubscriber>,
+    /// what the last reconcile dropped.
+    #[serde(skip)]
+    dropped: Vec<DroppedSubscription>,
+}
+
+/// A subscription whose subscriber process was gone, see `Sub::reconcile`.
+/// Subscribe again to resume it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DroppedSubscription {
+    pub topic: String,
+    pub publisher: Address,
+}
+
+/// A subscriber process, and the subscription it was spawned for.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SpawnedSubscriber {
+    address: Address,
+    subscription: Subscription,
+}
+
+/// JSON object keys must be strings, so subscriptions are stored as a list of pairs.
+mod subscription_list {
+    use super::{Subscriber, Subscription};
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::collections::HashMap;
+
+    pub fn serialize<S: Serializer>(
+        subscriptions: &HashMap<Subscription, Subscriber>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(subscriptions.iter())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<HashMap<Subscription, Subscriber>, D::Error> {
+        let subscriptions = Vec::<(Subscription, Subscriber)>::deserialize(deserializer)?;
+        Ok(subscriptions.into_iter().collect())
+    }
 }
 
 /// Represents a unique subscription identified by publisher and topic.
@@ -264,12 +720,18 @@ impl Sub {
 
         // try loading state
         let sub_instance = match Self::load_state(&kv) {
-            Ok(loaded_state) => loaded_state,
+            Ok(mut loaded_state) => {
+                loaded_state.reconcile()?;
+                loaded_state
+            }
             Err(_) => {
                 let new_state = Self {
                     subscriptions: HashMap::new(),
                     our: our.clone(),
                     kv: kv.clone(),
+                    keys: HashMap::new(),
+                    children: vec![],
+                    dropped: vec![],
                 };
                 new_state.save_state()?;
                 new_state
@@ -305,6 +767,96 @@ impl Sub {
         Ok(())
     }
 
+    /// Reconciles loaded state with the subscriber processes still running,
+    /// which `OnExit::Restart` keeps alive across our restarts.
+    /// Live processes of a known subscription are re-adopted, live ones without one are
+    /// unsubscribed, which makes them exit, and subscriptions whose process is gone are dropped.
+    /// This way no two processes ever deliver the same subscription.
+    /// Called when a `Sub` is loaded, see `dropped` for what that dropped.
+    ///
+    /// Pings share a budget of `RECONCILE_TIMEOUT` seconds. Processes left unchecked when
+    /// it runs out are assumed live, and their subscriptions kept.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the subscriptions dropped, or an error.
+    pub fn reconcile(&mut self) -> Result<Vec<DroppedSubscription>> {
+        for (subscription, subscriber) in &self.subscriptions {
+            if !self
+                .children
+                .iter()
+                .any(|child| child.address == subscriber.address)
+            {
+                self.children.push(SpawnedSubscriber {
+                    address: subscriber.address.clone(),
+                    subscription: subscription.clone(),
+                });
+            }
+        }
+
+        let ping = SubRequest::Ping;
+        let deadline = Instant::now() + Duration::from_secs(RECONCILE_TIMEOUT);
+        let mut live: Vec<Address> = vec![];
+        let mut unchecked: Vec<Address> = vec![];
+        for child in &self.children {
+            // a gone process fails right away, only hanging ones use up the budget.
+            let remaining = deadline.saturating_duration_since(Instant::now()).as_secs();
+            if remaining == 0 {
+                unchecked.push(child.address.clone());
+                continue;
+            }
+            if let Ok(Ok(_)) = Request::to(&child.address)
+                .body(&ping)
+                .send_and_await_response(remaining.min(5))
+            {
+                live.push(child.address.clone());
+            }
+        }
+
+        let mut dropped = vec![];
+        self.subscriptions.retain(|subscription, subscriber| {
+            let keep =
+                live.contains(&subscriber.address) || unchecked.contains(&subscriber.address);
+            if !keep {
+                dropped.push(DroppedSubscription {
+                    topic: subscription.topic.clone(),
+                    publisher: subscription.publisher.clone(),
+                });
+            }
+            keep
+        });
+
+        for child in &self.children {
+            let adopted = self
+                .subscriptions
+                .values()
+                .any(|subscriber| subscriber.address == child.address);
+            if live.contains(&child.address) && !adopted {
+                let unsub_request = SubRequest::Unsubscribe(UnsubscribeRequest {
+                    topic: child.subscription.topic.clone(),
+                });
+                Request::to(&child.address).body(&unsub_request).send()?;
+            }
+        }
+        // unchecked ones are kept too, the next reconcile gets to them.
+        let subscriptions = &self.subscriptions;
+        self.children.retain(|child| {
+            unchecked.contains(&child.address)
+                || subscriptions
+                    .values()
+                    .any(|subscriber| subscriber.address == child.address)
+        });
+
+        self.save_state()?;
+        self.dropped = dropped.clone();
+        Ok(dropped)
+    }
+
+    /// What the last `reconcile` dropped, including the one run when this `Sub` was loaded.
+    pub fn dropped(&self) -> &[DroppedSubscription] {
+        &self.dropped
+    }
+
     /// Subscribes to a topic from a specific sequence number.
     ///
     /// # Arguments
@@ -324,34 +876,35 @@ impl Sub {
         node: &str,
         sequence: Option<u64>,
     ) -> Result<(), SubError> {
-        let publisher_pkg = publisher_pkg.into();
-        let publisher_process = ProcessId::from((
-            format!("pub-{}", topic).as_str(),
-            publisher_pkg.package_name.as_str(),
-            publisher_pkg.publisher_node.as_str(),
-        ));
-
-        let publisher = Address::new(node.to_string(), publisher_process);
+        let publisher = publisher_address(topic, publisher_pkg, node);
 
         let subscription = Subscription {
             publisher: publisher.clone(),
             topic: topic.to_string(),
         };
 
-        if let Some(subscriber) = self.subscriptions.get(&subscription) {
-            // TODO: resubscribe, sending a normal subscribe request
+        if let Some(subscriber) = self.subscriptions.get_mut(&subscription) {
+            // resubscribe, the subscriber process forwards it and hands back the publisher's response.
             let req = SubRequest::Subscribe(SubscribeRequest {
                 topic: topic.to_string(),
                 from_sequence: sequence,
             });
 
-            Request::to(&subscriber.address).body(&req).send().unwrap();
+            let res = Request::to(&subscriber.address)
+                .body(&req)
+                .send_and_await_response(SUBSCRIBE_TIMEOUT)
+                .map_err(|e| SubError::SubInitError(e.to_string()))?
+                .map_err(|e| SubError::SubInitError(e.to_string()))?;
+            let sub_response = read_subscribe_response(res.body())?;
+
+            subscriber.latest_sequence = sequence.unwrap_or(sub_response.latest_sequence);
+            self.save_state()
+                .map_err(|e| SubError::SerializeError(e.to_string()))?;
 
             return Ok(());
         }
 
-        // TODO: more granular caps.
-        let our_caps = our_capabilities();
+        let our_caps = child_capabilities(&self.our, WasmType::Sub);
 
         // we spawn a subscriber.
         let wasm_path = format!("{}/pkg/sub.wasm", self.our.package_id());
@@ -361,35 +914,53 @@ impl Sub {
 
         let subscriber_address = Address::new(self.our.node.clone(), process);
 
+        // remembered right away, so that a restart before we're done can't orphan it.
+        self.children.push(SpawnedSubscriber {
+            address: subscriber_address.clone(),
+            subscription: subscription.clone(),
+        });
+        self.save_state()
+            .map_err(|e| SubError::SerializeError(e.to_string()))?;
+
         let sub_init = InitSubRequest {
             topic: topic.to_string(),
             parent: self.our.to_string(),
             forward_to: vec![],
             publisher: publisher.to_string(),
             from_sequence: sequence,
+            keys: self.keys.get(topic).cloned().unwrap_or_default(),
         };
 
         let res = Request::to(&subscriber_address)
             .body(&sub_init)
-            .send_and_await_response(10)
-            .unwrap()
-            .map_err(|e| SubError::SubInitError(e.to_string()))?;
-
-        let sub_response = serde_json::from_slice::<SubscribeResponse>(&res.body())
-            .map_err(|e| SubError::SerializeError(e.to_string()))?;
-
-        if !sub_response.success {
-            return Err(SubError::SubInitError(
-                sub_response.error.unwrap_or_default(),
-            ));
-        }
+            .send_and_await_response(SUBSCRIBE_TIMEOUT)
+            .map_err(|e| SubError::SubInitError(e.to_string()))
+            .and_then(|res| res.map_err(|e| SubError::SubInitError(e.to_string())))
+            .and_then(|res| read_subscribe_response(res.body()));
+        let sub_response = match res {
+            Ok(sub_response) => sub_response,
+            Err(e) => {
+                // the subscriber may still get through to the publisher, stop it
+                // rather than leave it delivering next to a retry's subscriber.
+                let unsub_request = SubRequest::Unsubscribe(UnsubscribeRequest {
+                    topic: topic.to_string(),
+                });
+                let _ = Request::to(&subscriber_address).body(&unsub_request).send();
+                self.children
+                    .retain(|child| child.address != subscriber_address);
+                let _ = self.save_state();
+                return Err(e);
+            }
+        };
 
         let subscriber = Subscriber {
             address: subscriber_address,
-            latest_sequence: sequence.unwrap_or(0),
+            latest_sequence: sequence.unwrap_or(sub_response.latest_sequence),
         };
 
         self.subscriptions.insert(subscription, subscriber);
+        self.save_state()
+            .map_err(|e| SubError::SerializeError(e.to_string()))?;
 
         Ok(())
     }
@@ -431,14 +1002,7 @@ impl Sub {
         publisher_pkg: T,
         node: &str,
     ) -> Result<(), SubError> {
-        let publisher_pkg = publisher_pkg.into();
-        let publisher_process = ProcessId::from((
-            format!("pub-{}", topic).as_str(),
-            publisher_pkg.package_name.as_str(),
-            publisher_pkg.publisher_node.as_str(),
-        ));
-
-        let publisher = Address::new(node.to_string(), publisher_process);
+        let publisher = publisher_address(topic, publisher_pkg, node);
 
         let subscription = Subscription {
             publisher,
@@ -446,6 +1010,11 @@ impl Sub {
         };
 
         if let Some(subscriber) = self.subscriptions.remove(&subscription) {
+            self.children
+                .retain(|child| child.address != subscriber.address);
+            self.save_state()
+                .map_err(|e| SubError::SerializeError(e.to_string()))?;
+
             // Send an unsubscribe request to the subscriber process
             let unsub_request = SubRequest::Unsubscribe(UnsubscribeRequest {
                 topic: topic.to_string(),
@@ -460,6 +1029,175 @@ impl Sub {
             Err(SubError::SubscriptionNotFound)
         }
     }
+
+    /// Forgets the subscription served by a subscriber process that reported
+    /// its topic closed with `SubRequest::TopicClosed`. The process has already exited.
+    ///
+    /// # Arguments
+    ///
+    /// * `subscriber` - The address of the subscriber process, the source of the request.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or a `SubError`.
+    pub fn topic_closed(&mut self, subscriber: &Address) -> Result<(), SubError> {
+        let before = self.subscriptions.len();
+        self.subscriptions
+            .retain(|_, existing| &existing.address != subscriber);
+        self.children.retain(|child| &child.address != subscriber);
+        if self.subscriptions.len() == before {
+            return Err(SubError::SubscriptionNotFound);
+        }
+        self.save_state()
+            .map_err(|e| SubError::SerializeError(e.to_string()))
+    }
+
+    /// Adds a key for an encrypted topic, and hands it to our subscriber processes for the topic,
+    /// which decrypt before delivering. Keep adding keys on rotation: every key-epoch stays
+    /// around, so history sealed with an older key stays readable.
+    ///
+    /// # Arguments
+    ///
+    /// * `topic` - The name of the topic.
+    /// * `key` - The key, as given to the publisher's `Pub::set_topic_key`.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or a `SubError`.
+    pub fn set_topic_key(&mut self, topic: &str, key: TopicKey) -> Result<(), SubError> {
+        encryption::validate_key(&key).map_err(|e| SubError::InvalidKey(e.to_string()))?;
+
+        let keys = self.keys.entry(topic.to_string()).or_default();
+        keys.retain(|existing| existing.key_epoch != key.key_epoch);
+        keys.push(key.clone());
+        let _ = self.save_state();
+
+        let req = SubRequest::SetKey(key);
+        for (subscription, subscriber) in &self.subscriptions {
+            if subscription.topic == topic {
+                Request::to(&subscriber.address)
+                    .body(&req)
+                    .send()
+                    .map_err(|e| SubError::SerializeError(e.to_string()))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Fetches a page of a topic's history directly from its publisher, without subscribing.
+    ///
+    /// # Arguments
+    ///
+    /// * `publisher_pkg` - The package ID of the publisher.
+    /// * `node` - The node of the publisher.
+    /// * `request` - The range to fetch. Pass the returned `next_cursor` as `cursor` to get the next page.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the page of history or a `SubError`.
+    pub fn fetch_history<T: Into<PackageId>>(
+        &self,
+        publisher_pkg: T,
+        node: &str,
+        request: FetchHistoryRequest,
+    ) -> Result<FetchHistoryResponse, SubError> {
+        let publisher = publisher_address(&request.topic, publisher_pkg, node);
+
+        let res = Request::to(&publisher)
+            .body(PubRequest::FetchHistory(request))
+            .send_and_await_response(10)
+            .map_err(|e| SubError::FetchHistoryError(e.to_string()))?
+            .map_err(|e| SubError::FetchHistoryError(e.to_string()))?;
+
+        let PubResponse::FetchHistory(mut res) = serde_json::from_slice::<PubResponse>(res.body())
+            .map_err(|e| SubError::SerializeError(e.to_string()))?
+        else {
+            return Err(SubError::FetchHistoryError(
+                "unexpected response from publisher".to_string(),
+            ));
+        };
+
+        if let Some(error) = res.error {
+            return Err(SubError::FetchHistoryError(error));
+        }
+
+        // history is stored as published: encrypted and/or compressed.
+        let keys = self
+            .keys
+            .get(&res.topic)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        for message in res.messages.iter_mut() {
+            let payload = encryption::decrypt(
+                keys,
+                &res.topic,
+                &mut message.headers,
+                std::mem::take(&mut message.payload),
+            )
+            .map_err(|e| SubError::DecryptionError(e.to_string()))?;
+            message.payload = compression::decompress(&mut message.headers, payload)
+                .map_err(|e| SubError::FetchHistoryError(e.to_string()))?;
+        }
+
+        Ok(res)
+    }
+}
+
+/// Address of the publisher process for a topic.
+fn publisher_address<T: Into<PackageId>>(topic: &str, publisher_pkg: T, node: &str) -> Address {
+    let publisher_pkg = publisher_pkg.into();
+    let publisher_process = ProcessId::from((
+        format!("pub-{}", topic).as_str(),
+        publisher_pkg.package_name.as_str(),
+        publisher_pkg.publisher_node.as_str(),
+    ));
+
+    Address::new(node.to_string(), publisher_process)
+}
+
+/// Parses the publisher's answer to a subscribe, forwarded by the subscriber process,
+/// turning a rejection or failure into a `SubError`.
+fn read_subscribe_response(body: &[u8]) -> Result<SubscribeResponse, SubError> {
+    let sub_response: SubscribeResponse = serde_json::from_slice::<LegacySubscribeResponse>(body)
+        .map_err(|e| SubError::SerializeError(e.to_string()))?
+        .into();
+
+    if let Some(rejection) = sub_response.rejection {
+        return Err(SubError::SubscriptionRejected(rejection));
+    }
+    if !sub_response.success {
+        return Err(SubError::SubInitError(
+            sub_response.error.unwrap_or_default(),
+        ));
+    }
+    Ok(sub_response)
+}
+
+/// A `SubscribeResponse` as publishers built before sequences and epochs send it.
+#[derive(Deserialize)]
+struct LegacySubscribeResponse {
+    success: bool,
+    topic: String,
+    error: Option<String>,
+    #[serde(default)]
+    rejection: Option<SubscribeRejection>,
+    #[serde(default)]
+    latest_sequence: u64,
+    #[serde(default)]
+    epoch: u64,
+}
+
+impl From<LegacySubscribeResponse> for SubscribeResponse {
+    fn from(saved: LegacySubscribeResponse) -> Self {
+        SubscribeResponse {
+            success: saved.success,
+            topic: saved.topic,
+            error: saved.error,
+            rejection: saved.rejection,
+            latest_sequence: saved.latest_sequence,
+            epoch: saved.epoch,
+        }
+    }
 }
 
 /// Errors that can occur in the subscriber operations.
@@ -471,6 +1209,20 @@ pub enum SubError {
     SubscriptionNotFound,
     SubInitError(String),
     UnsubscribeError(String),
+    FetchHistoryError(String),
+    SubscriptionRejected(SubscribeRejection),
+    InvalidKey(String),
+    DecryptionError(String),
+    MissingPayload {
+        topic: String,
+        sequence: u64,
+    },
+    DecodeError {
+        topic: String,
+        sequence: u64,
+        content_type: Option<String>,
+        error: String,
+    },
 }
 
 /// Errors that can occur in the publisher operations.
@@ -479,6 +1231,12 @@ pub enum PubError {
     TopicNotFound,
     SpawningError(String),
     NoPublisherProcessFound(String),
+    MessageTooLarge { size: u64, max_bytes: u64 },
+    PublishError(String),
+    EncodeError(String),
+    InvalidKey(String),
+    EncryptionError(String),
+    TopicExists(String),
 }
 
 impl std::fmt::Display for PubError {
@@ -489,6 +1247,18 @@ impl std::fmt::Display for PubError {
             PubError::NoPublisherProcessFound(s) => {
                 write!(f, "No publisher process found for: {}", s)
             }
+            PubError::MessageTooLarge { size, max_bytes } => write!(
+                f,
+                "Message of {} bytes exceeds the topic's history budget of {} bytes",
+                size, max_bytes
+            ),
+            PubError::PublishError(s) => write!(f, "Publish error: {}", s),
+            PubError::EncodeError(s) => write!(f, "Encode error: {}", s),
+            PubError::InvalidKey(s) => write!(f, "Invalid topic key: {}", s),
+            PubError::EncryptionError(s) => write!(f, "Encryption error: {}", s),
+            PubError::TopicExists(s) => {
+                write!(f, "Topic {} exists with a different config", s)
+            }
         }
     }
 }
@@ -502,9 +1272,47 @@ impl std::fmt::Display for SubError {
             SubError::SubscriptionNotFound => write!(f, "Subscription not found"),
             SubError::SubInitError(s) => write!(f, "Subscriber initialization error: {}", s),
             SubError::UnsubscribeError(s) => write!(f, "Unsubscribe error: {}", s),
+            SubError::FetchHistoryError(s) => write!(f, "Fetch history error: {}", s),
+            SubError::SubscriptionRejected(SubscribeRejection::UnknownTopic(topic)) => {
+                write!(f, "Subscription rejected: publisher has topic {}", topic)
+            }
+            SubError::SubscriptionRejected(SubscribeRejection::AccessDenied) => {
+                write!(f, "Subscription rejected: access denied")
+            }
+            SubError::InvalidKey(s) => write!(f, "Invalid topic key: {}", s),
+            SubError::DecryptionError(s) => write!(f, "Decryption error: {}", s),
+            SubError::MissingPayload { topic, sequence } => {
+                write!(f, "Message {} on topic {} has no payload", sequence, topic)
+            }
+            SubError::DecodeError {
+                topic,
+                sequence,
+                content_type,
+                error,
+            } => write!(
+                f,
+                "Failed to decode message {} on topic {} (content-type: {}): {}",
+                sequence,
+                topic,
+                content_type.as_deref().unwrap_or("none"),
+                error
+            ),
         }
     }
 }
 
 impl std::error::Error for SubError {}
 impl std::error::Error for PubError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_reserved_headers() {
+        let header = |name: &str| vec![(name.to_string(), "x".to_string())];
+        assert!(check_headers(&header("content-type")).is_ok());
+        assert!(check_headers(&header("pubsub-content-encoding")).is_err());
+        assert!(check_headers(&header("PubSub-Encryption")).is_err());
+    }
+}
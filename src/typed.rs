@@ -0,0 +1,195 @@
+use anyhow::Result;
+use kinode_process_lib::get_blob;
+use serde::{de::DeserializeOwned, Serialize};
+use std::marker::PhantomData;
+
+use crate::kinode::process::pub_::PublishRequest;
+use crate::pubsub::{Pub, PubError, Sub, SubError};
+
+/// Header naming the codec a typed message was encoded with.
+pub const CONTENT_TYPE_HEADER: &str = "content-type";
+
+/// Turns typed values into message payloads and back.
+pub trait Codec {
+    /// Sent as the `content-type` header, and checked when decoding.
+    const CONTENT_TYPE: &'static str;
+
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>>;
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T>;
+}
+
+/// The default codec.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+impl Codec for Json {
+    const CONTENT_TYPE: &'static str = "application/json";
+
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
+
+/// A handle for publishing values of one type to a topic, see `Pub::topic`.
+pub struct Topic<'a, T, C = Json> {
+    pubb: &'a mut Pub,
+    name: String,
+    _types: PhantomData<(T, C)>,
+}
+
+impl<'a, T: Serialize, C: Codec> Topic<'a, T, C> {
+    pub(crate) fn new(pubb: &'a mut Pub, name: &str) -> Self {
+        Topic {
+            pubb,
+            name: name.to_string(),
+            _types: PhantomData,
+        }
+    }
+
+    /// The name of the topic.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Encodes and publishes a value, creating the topic if it doesn't exist.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - The value to publish.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or a `PubError`.
+    pub fn publish(&mut self, value: &T) -> Result<(), PubError> {
+        self.publish_message(None, value)
+    }
+
+    /// Encodes and publishes a keyed value, see `Pub::publish_keyed`.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key of the entity this value updates.
+    /// * `value` - The value to publish.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or a `PubError`.
+    pub fn publish_keyed(&mut self, key: &str, value: &T) -> Result<(), PubError> {
+        self.publish_message(Some(key), value)
+    }
+
+    fn publish_message(&mut self, key: Option<&str>, value: &T) -> Result<(), PubError> {
+        let bytes = C::encode(value).map_err(|e| PubError::EncodeError(e.to_string()))?;
+        let headers = vec![(CONTENT_TYPE_HEADER.to_string(), C::CONTENT_TYPE.to_string())];
+        self.pubb.publish_message(&self.name, key, headers, &bytes)
+    }
+}
+
+/// A message received from a subscription, with its payload decoded.
+#[derive(Debug, Clone)]
+pub struct Received<T> {
+    pub topic: String,
+    pub sequence: u64,
+    pub epoch: u64,
+    pub key: Option<String>,
+    pub headers: Vec<(String, String)>,
+    pub value: T,
+}
+
+impl Pub {
+    /// Returns a typed handle for a topic, encoding values as JSON.
+    ///
+    /// # Arguments
+    ///
+    /// * `topic` - The name of the topic.
+    ///
+    /// # Returns
+    ///
+    /// A `Topic` that publishes values of type `T`.
+    pub fn topic<T: Serialize>(&mut self, topic: &str) -> Topic<'_, T, Json> {
+        Topic::new(self, topic)
+    }
+
+    /// Returns a typed handle for a topic, encoding values with the codec `C`.
+    ///
+    /// # Arguments
+    ///
+    /// * `topic` - The name of the topic.
+    ///
+    /// # Returns
+    ///
+    /// A `Topic` that publishes values of type `T`.
+    pub fn topic_with_codec<T: Serialize, C: Codec>(&mut self, topic: &str) -> Topic<'_, T, C> {
+        Topic::new(self, topic)
+    }
+}
+
+impl Sub {
+    /// Decodes the payload of a `SubRequest::Publish` as JSON.
+    /// Must be called while handling that request, the payload is its blob.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The publish request received from the subscriber process.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the decoded message or a `SubError`.
+    pub fn decode<T: DeserializeOwned>(request: &PublishRequest) -> Result<Received<T>, SubError> {
+        Self::decode_with::<T, Json>(request)
+    }
+
+    /// Decodes the payload of a `SubRequest::Publish` with the codec `C`.
+    /// Must be called while handling that request, the payload is its blob.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The publish request received from the subscriber process.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the decoded message or a `SubError`.
+    pub fn decode_with<T: DeserializeOwned, C: Codec>(
+        request: &PublishRequest,
+    ) -> Result<Received<T>, SubError> {
+        let content_type = request
+            .headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(CONTENT_TYPE_HEADER))
+            .map(|(_, value)| value.clone());
+
+        let decode_error = |error: String| SubError::DecodeError {
+            topic: request.topic.clone(),
+            sequence: request.sequence,
+            content_type: content_type.clone(),
+            error,
+        };
+
+        // untyped publishes carry no content-type, try them anyway.
+        if let Some(content_type) = &content_type {
+            if content_type != C::CONTENT_TYPE {
+                return Err(decode_error(format!("expected {}", C::CONTENT_TYPE)));
+            }
+        }
+
+        let blob = get_blob().ok_or(SubError::MissingPayload {
+            topic: request.topic.clone(),
+            sequence: request.sequence,
+        })?;
+        let value = C::decode(&blob.bytes).map_err(|e| decode_error(e.to_string()))?;
+
+        Ok(Received {
+            topic: request.topic.clone(),
+            sequence: request.sequence,
+            epoch: request.epoch,
+            key: request.key.clone(),
+            headers: request.headers.clone(),
+            value,
+        })
+    }
+}